@@ -1,61 +1,12 @@
-use std::{fs::File, io::Read};
-
-use vm::emulator::Emulator;
+use vm::{assembler::read_bytecode, emulator::Emulator, hexdump::hex_dump};
 
 use crate::vm::arch::{
-    CmpFlags, InstructionDecodeIndices, InstructionOpcodes, Registers, Syscalls, VMConsts,
+    AddOperandMode, CmpFlags, InstructionDecodeIndices, InstructionOpcodes, Registers, Syscalls,
+    VMConsts,
 };
 
 pub mod vm;
 
-fn read_file_contents(file_path: &str) -> std::io::Result<Vec<u8>> {
-    // Open the file in read-only mode
-    let mut file = File::open(file_path)?;
-
-    // Get the file metadata to determine its size
-    let metadata = file.metadata()?;
-    let file_size = metadata.len() as usize;
-
-    // Create a Vec<u8> with the capacity of the file size
-    let mut buffer = Vec::with_capacity(file_size);
-
-    // Read the file contents into the buffer
-    file.read_to_end(&mut buffer)?;
-
-    Ok(buffer)
-}
-
-fn print_hex_dump(data: &[u8]) {
-    // Define the number of bytes per line in the hexdump
-    const BYTES_PER_LINE: usize = 16;
-
-    for (i, chunk) in data.chunks(BYTES_PER_LINE).enumerate() {
-        print!("{:08x}: ", i * BYTES_PER_LINE);
-
-        // Print hexadecimal representation
-        for byte in chunk {
-            print!("{:02x} ", byte);
-        }
-
-        // Pad the last line in case the chunk size is smaller than BYTES_PER_LINE
-        for _ in chunk.len()..BYTES_PER_LINE {
-            print!("   ");
-        }
-
-        // Print the ASCII representation
-        print!(" | ");
-        for &byte in chunk {
-            if byte.is_ascii_graphic() {
-                print!("{}", byte as char);
-            } else {
-                print!(".");
-            }
-        }
-
-        println!();
-    }
-}
-
 fn main() {
     /*
     let emu_memory: Vec<u8> = vec![
@@ -105,9 +56,9 @@ fn main() {
         0x20, 0x40, 0x24, 0x40, 0x04, 0x40, 0x20, 0x40, 0x94, 0x40, 0x0a, 0x40,
     ];
     */
-    let emu_memory = read_file_contents("121.bin").unwrap();
+    let emu_memory = read_bytecode("121.bin").unwrap();
 
-    print_hex_dump(&emu_memory);
+    print!("{}", hex_dump(&emu_memory, 0));
 
     let mut raw_mem: Vec<u8> = vec![0; 0x407]; // 1024 bytes + 7 registers
 
@@ -115,7 +66,7 @@ fn main() {
         *place = *data
     }
 
-    let config_191 = VMConsts {
+    let _config_191 = VMConsts {
         opcodes: InstructionOpcodes {
             imm: 0x10,
             add: 0x1,
@@ -125,11 +76,13 @@ fn main() {
             cmp: 0x40,
             jmp: 0x80,
             sys: 0x8,
+            halt: None,
         },
         syscalls: Syscalls {
             open: 0x20,
             read_memory: 0x8,
             write: 0x10,
+            exit: None,
         },
         instruction_indices: InstructionDecodeIndices {
             opcode: 1,
@@ -145,6 +98,7 @@ fn main() {
             i: 0x4,
             f: 0x2,
             none: 0x0,
+            extra: &[],
         },
         cmp_flags: CmpFlags {
             smaller: 0x2,
@@ -153,9 +107,16 @@ fn main() {
             not_equals: 0x4,
             zero: 0x8,
         },
+        scratch: None,
+        code_region_end: None,
+        add_operand: AddOperandMode::Register,
+        immediate_mask: None,
+        syscall_abi: None,
+        flags_on_arith: false,
+        memory_layout: None,
     };
 
-    let config_200 = VMConsts {
+    let _config_200 = VMConsts {
         opcodes: InstructionOpcodes {
             imm: 0x40,
             add: 0x4,
@@ -165,11 +126,13 @@ fn main() {
             cmp: 0x8,
             jmp: 0x2,
             sys: 0x80,
+            halt: None,
         },
         syscalls: Syscalls {
             open: 0x10,
             read_memory: 0x2,
             write: 0x20,
+            exit: None,
         },
         instruction_indices: InstructionDecodeIndices {
             opcode: 2,
@@ -185,6 +148,7 @@ fn main() {
             i: 0x1,
             f: 0x10,
             none: 0x0,
+            extra: &[],
         },
         cmp_flags: CmpFlags {
             smaller: 0x2,
@@ -193,6 +157,13 @@ fn main() {
             not_equals: 0x1,
             zero: 0x4,
         },
+        scratch: None,
+        code_region_end: None,
+        add_operand: AddOperandMode::Register,
+        immediate_mask: None,
+        syscall_abi: None,
+        flags_on_arith: false,
+        memory_layout: None,
     };
 
     let config_201 = VMConsts {
@@ -205,11 +176,13 @@ fn main() {
             cmp: 0x40,
             jmp: 0x20,
             sys: 0x4,
+            halt: None,
         },
         syscalls: Syscalls {
             open: 0x8,
             read_memory: 0x1,
             write: 0x4,
+            exit: None,
         },
         instruction_indices: InstructionDecodeIndices {
             opcode: 0,
@@ -225,6 +198,7 @@ fn main() {
             i: 0x4,
             f: 0x20,
             none: 0x0,
+            extra: &[],
         },
         cmp_flags: CmpFlags {
             smaller: 0x2,
@@ -233,19 +207,24 @@ fn main() {
             not_equals: 0x8,
             zero: 0x4,
         },
+        scratch: None,
+        code_region_end: None,
+        add_operand: AddOperandMode::Register,
+        immediate_mask: None,
+        syscall_abi: None,
+        flags_on_arith: false,
+        memory_layout: None,
     };
 
     let mut emu = Emulator::new(raw_mem, config_201);
+    emu.set_trace(true);
 
     println!("Starting emulator");
 
     loop {
-        match emu.execute_next_instruction() {
-            Err(err) => {
-                println!("{}", err);
-                break;
-            }
-            Ok(_) => {}
+        if let Err(err) = emu.execute_next_instruction() {
+            println!("{}", err);
+            break;
         }
     }
 }
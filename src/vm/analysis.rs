@@ -0,0 +1,230 @@
+use super::arch::{reg, Instruction, VMConsts, REG_NONE};
+
+//Static helpers that inspect a program without running it. Undecodable instructions are skipped
+
+//Returns (ip, syscall number) for every SYS in the program. Use ctx.syscalls.name to tell which one it is
+pub fn find_syscalls(program: &[u8], ctx: &VMConsts) -> Vec<(u8, u8)> {
+    program
+        .chunks(3)
+        .enumerate()
+        .filter_map(|(ip, instruction_bytes)| {
+            match Instruction::from_bytes(instruction_bytes, ctx.instruction_indices, ctx.opcodes) {
+                Some(Instruction::Sys { num, .. }) => Some((ip as u8, num)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+//Returns (ip, dst register, value) for every IMM in the program
+pub fn extract_immediates(program: &[u8], ctx: &VMConsts) -> Vec<(u8, reg, u8)> {
+    program
+        .chunks(3)
+        .enumerate()
+        .filter_map(|(ip, instruction_bytes)| {
+            match Instruction::from_bytes(instruction_bytes, ctx.instruction_indices, ctx.opcodes) {
+                Some(Instruction::Imm { dst, val }) => Some((ip as u8, dst, val)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+//Net change to the stack size over the program, read top to bottom: +1 per STK that pushes, -1 per STK that pops.
+//Jumps are ignored, so it's only exact for straight-line code, e.g. a slice of the program holding one block
+pub fn stack_balance(program: &[u8], ctx: &VMConsts) -> i32 {
+    program
+        .chunks(3)
+        .filter_map(|instruction_bytes| {
+            Instruction::from_bytes(instruction_bytes, ctx.instruction_indices, ctx.opcodes)
+        })
+        .map(|instruction| match instruction {
+            Instruction::Stk { pop, push } => (push != REG_NONE) as i32 - (pop != REG_NONE) as i32,
+            _ => 0,
+        })
+        .sum()
+}
+
+//Number of conditional jumps, i.e. JMPs with flags set. A rough measure of how many paths a program has
+pub fn branch_count(program: &[u8], ctx: &VMConsts) -> usize {
+    program
+        .chunks(3)
+        .filter(|instruction_bytes| {
+            matches!(
+                Instruction::from_bytes(instruction_bytes, ctx.instruction_indices, ctx.opcodes),
+                Some(Instruction::Jmp { flags, .. }) if flags != 0
+            )
+        })
+        .count()
+}
+
+//Returns (ip, target) for every JMP whose target register was loaded by an IMM.
+//Values are tracked in straight-line order and forgotten whenever the register is clobbered or after an unconditional jump
+pub fn constant_jump_targets(program: &[u8], ctx: &VMConsts) -> Vec<(u8, u8)> {
+    jump_target_sources(program, ctx)
+        .into_iter()
+        .map(|(_, ip, target)| (ip, target))
+        .collect()
+}
+
+//Same as constant_jump_targets, plus the ip of the IMM that loaded the target, as (imm ip, jmp ip, target)
+pub fn jump_target_sources(program: &[u8], ctx: &VMConsts) -> Vec<(u8, u8, u8)> {
+    let mut targets = vec![];
+    let mut known: [Option<(u8, u8)>; 256] = [None; 256]; // (imm ip, value) by register
+
+    for (ip, instruction_bytes) in program.chunks(3).enumerate() {
+        let instruction = match Instruction::from_bytes(
+            instruction_bytes,
+            ctx.instruction_indices,
+            ctx.opcodes,
+        ) {
+            Some(instruction) => instruction,
+            None => {
+                known = [None; 256];
+                continue;
+            }
+        };
+
+        match instruction {
+            Instruction::Imm { dst, val } => known[dst as usize] = Some((ip as u8, val)),
+            Instruction::Add { dst, .. }
+            | Instruction::Ldm { dst, .. }
+            | Instruction::Sys { dst, .. }
+            | Instruction::Stk { pop: dst, .. } => known[dst as usize] = None,
+            Instruction::Jmp { flags, dst } => {
+                if let Some((source, target)) = known[dst as usize] {
+                    targets.push((source, ip as u8, target));
+                }
+                if flags == 0 {
+                    known = [None; 256];
+                }
+            }
+            // Only a jump can get past a halt, and its registers could hold anything
+            Instruction::Halt => known = [None; 256],
+            Instruction::Cmp { .. } | Instruction::Stm { .. } => {}
+        }
+    }
+    targets
+}
+
+//Whether two programs, each under its own config, do the same thing instruction by instruction.
+//Registers, syscalls and jump flags are compared by meaning rather than by byte. A program that doesn't fully decode isn't equivalent to anything
+pub fn programs_equivalent(a: &[u8], a_consts: &VMConsts, b: &[u8], b_consts: &VMConsts) -> bool {
+    match (
+        canonical_program(a, a_consts),
+        canonical_program(b, b_consts),
+    ) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn canonical_program(program: &[u8], ctx: &VMConsts) -> Option<Vec<String>> {
+    program
+        .chunks(3)
+        .map(|instruction_bytes| {
+            Instruction::from_bytes(instruction_bytes, ctx.instruction_indices, ctx.opcodes)
+                .map(|instruction| canonical_instruction(instruction, ctx))
+        })
+        .collect()
+}
+
+//Assembly for the instruction with the config specific numbers (syscalls and flags) replaced by names
+fn canonical_instruction(instruction: Instruction, ctx: &VMConsts) -> String {
+    let assembly = instruction.to_assembly(ctx);
+    // SYS and JMP both have their register last
+    let dst = assembly.rsplit(' ').next().unwrap_or_default();
+    match instruction {
+        Instruction::Sys { num, .. } => match ctx.syscalls.name(num) {
+            Some(name) => format!("SYS {} {}", name, dst),
+            None => assembly,
+        },
+        Instruction::Jmp { flags, .. } => {
+            let cmp_flags = ctx.cmp_flags;
+            let names = [
+                (cmp_flags.smaller, "smaller"),
+                (cmp_flags.bigger, "bigger"),
+                (cmp_flags.equals, "equals"),
+                (cmp_flags.not_equals, "not_equals"),
+                (cmp_flags.zero, "zero"),
+            ];
+            let mut condition: Vec<String> = names
+                .iter()
+                .filter(|(flag, _)| flags & flag != 0)
+                .map(|(_, name)| name.to_string())
+                .collect();
+            let known = names.iter().fold(0, |known, (flag, _)| known | flag);
+            if flags & !known != 0 {
+                condition.push(format!("{:#04x}", flags & !known));
+            }
+            format!("JMP {} {}", condition.join("|"), dst)
+        }
+        _ => assembly,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{assembler::assemble, testing::consts};
+
+    #[test]
+    fn finds_every_syscall_site() {
+        let ctx = consts();
+        let src = "IMM a 1\nSYS 4 d\nIMM b 2\nSYS 8 a";
+        let program = assemble(src.to_string(), ctx).unwrap();
+        let sites = find_syscalls(&program, &ctx);
+        assert_eq!(sites, vec![(1, ctx.syscalls.write), (3, ctx.syscalls.open)]);
+        let names: Vec<_> = sites
+            .iter()
+            .map(|&(_, num)| ctx.syscalls.name(num))
+            .collect();
+        assert_eq!(names, vec![Some("write"), Some("open")]);
+    }
+
+    #[test]
+    fn same_source_under_two_configs_is_equivalent() {
+        let src = "IMM a 5\nIMM b 3\nADD a b\nCMP a b\nSTK NONE a\nJMP E d\nSYS 4 d";
+        let ctx = consts();
+        let mut other = consts();
+        (other.opcodes.imm, other.opcodes.sys) = (other.opcodes.sys, other.opcodes.imm);
+        (other.registers.a, other.registers.d) = (other.registers.d, other.registers.a);
+        other.instruction_indices.opcode = 2;
+        other.instruction_indices.right_param = 0;
+        let program = assemble(src.to_string(), ctx).unwrap();
+        let reencoded = assemble(src.to_string(), other).unwrap();
+        assert_ne!(program, reencoded);
+        assert!(programs_equivalent(&program, &ctx, &reencoded, &other));
+
+        let changed = assemble(src.replace("IMM b 3", "IMM b 4"), other).unwrap();
+        assert!(!programs_equivalent(&program, &ctx, &changed, &other));
+    }
+
+    #[test]
+    fn extracts_every_immediate() {
+        let ctx = consts();
+        let regs = ctx.registers;
+        let src = "IMM a 1\nADD a b\nIMM b 0x20\nIMM c 255";
+        let program = assemble(src.to_string(), ctx).unwrap();
+        assert_eq!(
+            extract_immediates(&program, &ctx),
+            vec![(0, regs.a, 1), (2, regs.b, 0x20), (3, regs.c, 255)]
+        );
+    }
+
+    #[test]
+    fn counts_only_conditional_jumps() {
+        let ctx = consts();
+        let src = "CMP a b\nJMP E end\nJMP L end\nJMP ALWAYS end\nend: IMM a 1";
+        let program = assemble(src.to_string(), ctx).unwrap();
+        assert_eq!(branch_count(&program, &ctx), 2);
+    }
+
+    #[test]
+    fn push_push_pop_is_a_net_push() {
+        let ctx = consts();
+        let src = "STK NONE a\nSTK NONE b\nIMM c 1\nSTK c NONE\nSTK a a";
+        let program = assemble(src.to_string(), ctx).unwrap();
+        assert_eq!(stack_balance(&program, &ctx), 1);
+    }
+}
@@ -1,6 +1,8 @@
-use std::fmt;
+use std::{error, fmt, ops::Range};
 
+#[allow(non_camel_case_types)]
 pub type reg = u8;
+pub type ExtraRegister = (&'static str, reg, u16); // (name, byte, offset from reg_base)
 
 #[derive(Clone, Copy, Debug)]
 pub struct InstructionOpcodes {
@@ -12,6 +14,23 @@ pub struct InstructionOpcodes {
     pub cmp: u8,
     pub jmp: u8,
     pub sys: u8,
+    pub halt: Option<u8>, // Not every variant has a halt instruction
+}
+
+impl InstructionOpcodes {
+    //Every opcode byte with its mnemonic. halt is left out since not every config has it
+    pub fn all(&self) -> [(u8, &'static str); 8] {
+        [
+            (self.imm, "IMM"),
+            (self.add, "ADD"),
+            (self.stk, "STK"),
+            (self.stm, "STM"),
+            (self.ldm, "LDM"),
+            (self.cmp, "CMP"),
+            (self.jmp, "JMP"),
+            (self.sys, "SYS"),
+        ]
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -19,6 +38,7 @@ pub struct Syscalls {
     pub open: u8,
     pub read_memory: u8,
     pub write: u8,
+    pub exit: Option<u8>, // Not every variant has an exit syscall
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -28,6 +48,234 @@ pub struct VMConsts {
     pub registers: Registers,
     pub instruction_indices: InstructionDecodeIndices,
     pub cmp_flags: CmpFlags,
+    pub scratch: Option<reg>, // Register the assembler may clobber when expanding pseudo instructions. Defaults to d
+    pub code_region_end: Option<u16>, // Fetching an instruction that reaches this address is an error. None lets code run into data
+    pub add_operand: AddOperandMode,
+    pub immediate_mask: Option<u8>, // Bits of an IMM value the VM actually uses. None uses all 8
+    pub syscall_abi: Option<SyscallAbi>, // Defaults to a, b and c
+    pub flags_on_arith: bool,       // ADD sets the zero flag when its result is 0
+    pub memory_layout: Option<MemoryLayout>, // Defaults to data at 0x300 and registers at 0x400
+}
+
+//Where everything lives in memory. Code goes from 0 up to the data page
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryLayout {
+    pub ram_base: u16, // Start of the data page
+    pub ram_size: u16, // At most 0x100, since data is addressed by a u8 offset
+    pub reg_base: u16, // Registers are stored here in a, b, c, d, s, i, f order
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        MemoryLayout {
+            ram_base: 0x300,
+            ram_size: 0x100,
+            reg_base: 0x400,
+        }
+    }
+}
+
+impl MemoryLayout {
+    pub fn code(&self) -> Range<u16> {
+        0..self.ram_base
+    }
+
+    //Ranges stop at the end of the address space, validate rejects the layouts that would go past it
+    pub fn ram(&self) -> Range<u16> {
+        self.ram_base..self.ram_base.saturating_add(self.ram_size)
+    }
+
+    pub fn registers(&self) -> Range<u16> {
+        self.reg_base..self.reg_base.saturating_add(7)
+    }
+}
+
+//Registers syscalls take their arguments from, e.g. write(arg1 = fd, arg2 = offset, arg3 = count)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyscallAbi {
+    pub arg1: reg,
+    pub arg2: reg,
+    pub arg3: reg,
+}
+
+//How ADD reads its second operand
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddOperandMode {
+    #[default]
+    Register, // dst += value of the src register
+    Immediate, // dst += src, taken as a literal
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ConfigError {
+    InvalidScratchRegister {
+        register: reg,
+    },
+    InvalidDecodeIndices {
+        indices: InstructionDecodeIndices,
+    },
+    InvalidMemoryLayout {
+        layout: MemoryLayout,
+    },
+    EncodingMismatch {
+        instruction: Instruction,
+        decoded: Option<Instruction>,
+    },
+    InvalidExtraRegister {
+        name: &'static str,
+    },
+}
+
+impl error::Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ConfigError::InvalidScratchRegister { register } => {
+                write!(
+                    f,
+                    "Invalid scratch register: {}. It must be one of a, b, c or d",
+                    register
+                )
+            }
+            ConfigError::InvalidMemoryLayout { layout } => {
+                write!(
+                    f,
+                    "Invalid memory layout: data at {:#x} ({:#x} bytes), registers at {:#x}. The data page must be 1 to 0x100 bytes and not overlap the registers",
+                    layout.ram_base, layout.ram_size, layout.reg_base
+                )
+            }
+            ConfigError::InvalidExtraRegister { name } => {
+                write!(
+                    f,
+                    "Invalid extra register: {}. It must come after f and stay out of the data page",
+                    name
+                )
+            }
+            ConfigError::InvalidDecodeIndices { indices } => {
+                write!(
+                    f,
+                    "Invalid decode indices: opcode {}, left_param {}, right_param {}. They must be 0, 1 and 2 in some order",
+                    indices.opcode, indices.left_param, indices.right_param
+                )
+            }
+            ConfigError::EncodingMismatch {
+                instruction,
+                decoded,
+            } => match decoded {
+                Some(decoded) => write!(
+                    f,
+                    "{} encodes to bytes that decode as {}",
+                    instruction, decoded
+                ),
+                None => write!(f, "{} encodes to bytes that don't decode", instruction),
+            },
+        }
+    }
+}
+
+impl VMConsts {
+    pub fn scratch_register(&self) -> reg {
+        self.scratch.unwrap_or(self.registers.d)
+    }
+
+    pub fn layout(&self) -> MemoryLayout {
+        self.memory_layout.unwrap_or_default()
+    }
+
+    pub fn syscall_abi(&self) -> SyscallAbi {
+        self.syscall_abi.unwrap_or(SyscallAbi {
+            arg1: self.registers.a,
+            arg2: self.registers.b,
+            arg3: self.registers.c,
+        })
+    }
+
+    //Mnemonics whose opcode is set (non zero) and not shared with another instruction, so they decode unambiguously.
+    //Only says which instructions exist, how ADD and IMM read their operands is up to add_operand and immediate_mask
+    pub fn enabled_ops(&self) -> Vec<&'static str> {
+        let mut ops = self.opcodes.all().to_vec();
+        ops.push((self.opcodes.halt.unwrap_or(0), "HALT"));
+        ops.iter()
+            .filter(|(opcode, _)| {
+                *opcode != 0 && ops.iter().filter(|(other, _)| other == opcode).count() == 1
+            })
+            .map(|(_, mnemonic)| *mnemonic)
+            .collect()
+    }
+
+    //Encodes one of every instruction and decodes it back. Shared opcodes or overlapping indices make them disagree
+    pub fn self_check(&self) -> Result<(), ConfigError> {
+        // Out of range indices would panic in to_bytes rather than fail the check
+        self.instruction_indices.validate()?;
+        let (a, b) = (self.registers.a, self.registers.b);
+        let mut instructions = vec![
+            Instruction::Imm { dst: a, val: 1 },
+            Instruction::Add { dst: a, src: b },
+            Instruction::Stk { pop: a, push: b },
+            Instruction::Stm { dst: a, src: b },
+            Instruction::Ldm { dst: a, src: b },
+            Instruction::Cmp { left: a, right: b },
+            Instruction::Jmp { flags: 0, dst: a },
+            Instruction::Sys { num: 1, dst: a },
+        ];
+        if self.opcodes.halt.is_some() {
+            instructions.push(Instruction::Halt);
+        }
+        for instruction in instructions {
+            let bytes = instruction.to_bytes(self.instruction_indices, self.opcodes);
+            let decoded = Instruction::from_bytes(&bytes, self.instruction_indices, self.opcodes);
+            if decoded != Some(instruction) {
+                return Err(ConfigError::EncodingMismatch {
+                    instruction,
+                    decoded,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.instruction_indices.validate()?;
+
+        let layout = self.layout();
+        let (Some(ram_end), Some(registers_end)) = (
+            layout.ram_base.checked_add(layout.ram_size),
+            layout.reg_base.checked_add(7),
+        ) else {
+            return Err(ConfigError::InvalidMemoryLayout { layout });
+        };
+        if !(1..=0x100).contains(&layout.ram_size)
+            || (layout.ram_base < registers_end && layout.reg_base < ram_end)
+        {
+            return Err(ConfigError::InvalidMemoryLayout { layout });
+        }
+        let ram = layout.ram();
+
+        // s, i and f have a meaning to the VM, so clobbering them would break the program
+        let scratch = self.scratch_register();
+        let general = [
+            self.registers.a,
+            self.registers.b,
+            self.registers.c,
+            self.registers.d,
+        ];
+        if !general.contains(&scratch) {
+            return Err(ConfigError::InvalidScratchRegister { register: scratch });
+        }
+
+        for (name, _, offset) in self.registers.extra {
+            let in_place = *offset >= 7
+                && layout
+                    .reg_base
+                    .checked_add(*offset)
+                    .is_some_and(|location| !ram.contains(&location));
+            if !in_place {
+                return Err(ConfigError::InvalidExtraRegister { name });
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -39,6 +287,41 @@ pub struct CmpFlags {
     pub zero: u8,       // left == 0 && right == 0
 }
 
+//The flags register by meaning rather than by the config's bits
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlagSet {
+    pub smaller: bool,
+    pub bigger: bool,
+    pub equals: bool,
+    pub not_equals: bool,
+    pub zero: bool,
+}
+
+impl CmpFlags {
+    pub fn decode(&self, flags: u8) -> FlagSet {
+        FlagSet {
+            smaller: flags & self.smaller != 0,
+            bigger: flags & self.bigger != 0,
+            equals: flags & self.equals != 0,
+            not_equals: flags & self.not_equals != 0,
+            zero: flags & self.zero != 0,
+        }
+    }
+
+    pub fn encode(&self, flags: FlagSet) -> u8 {
+        [
+            (flags.smaller, self.smaller),
+            (flags.bigger, self.bigger),
+            (flags.equals, self.equals),
+            (flags.not_equals, self.not_equals),
+            (flags.zero, self.zero),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |byte, (_, bit)| byte | bit)
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Registers {
     pub a: u8,
@@ -49,6 +332,7 @@ pub struct Registers {
     pub i: u8,
     pub f: u8,
     pub none: u8,
+    pub extra: &'static [ExtraRegister], // Registers past f, for VMs that have more
 }
 
 /*
@@ -83,6 +367,18 @@ pub const REG_F: reg = 0x8;
 
 pub const REG_NONE: reg = 0x0;
 
+impl Syscalls {
+    pub fn name(&self, num: u8) -> Option<&'static str> {
+        match num {
+            num if num == self.open => Some("open"),
+            num if num == self.read_memory => Some("read_memory"),
+            num if num == self.write => Some("write"),
+            num if Some(num) == self.exit => Some("exit"),
+            _ => None,
+        }
+    }
+}
+
 impl Registers {
     pub fn reg_byte_to_str(&self, reg_value: reg) -> &'static str {
         match reg_value {
@@ -94,12 +390,22 @@ impl Registers {
             reg if reg == self.i => "i",
             reg if reg == self.f => "f",
             REG_NONE => "NONE",
-            _ => "Unknown",
+            _ => self
+                .extra_register(reg_value)
+                .map_or("Unknown", |(name, _, _)| name),
         }
     }
 
+    fn extra_register(&self, reg_value: reg) -> Option<ExtraRegister> {
+        self.extra
+            .iter()
+            .find(|(_, byte, _)| *byte == reg_value)
+            .copied()
+    }
+
+    //Not case sensitive, so A and none work too
     pub fn reg_str_to_byte(&self, reg_str: &str) -> Option<u8> {
-        Some(match reg_str {
+        Some(match reg_str.to_ascii_lowercase().as_str() {
             "a" => self.a,
             "b" => self.b,
             "c" => self.c,
@@ -107,36 +413,57 @@ impl Registers {
             "s" => self.s,
             "i" => self.i,
             "f" => self.f,
-            "NONE" => 0,
-            _ => return None,
+            "none" => 0,
+            name => {
+                self.extra
+                    .iter()
+                    .find(|(extra, _, _)| extra.eq_ignore_ascii_case(name))?
+                    .1
+            }
         })
     }
-    pub fn reg_to_mem_location(&self, reg_value: reg) -> Option<u16> {
+    //Every real register, NONE excluded
+    pub fn all(&self) -> [reg; 7] {
+        [self.a, self.b, self.c, self.d, self.s, self.i, self.f]
+    }
+
+    //Where every register lives, extra ones included, for making sure memory reaches all of them
+    pub fn mem_locations(&self, reg_base: u16) -> Vec<u16> {
+        self.all()
+            .into_iter()
+            .chain(self.extra.iter().map(|(_, register, _)| *register))
+            .filter_map(|register| self.reg_to_mem_location(register, reg_base))
+            .collect()
+    }
+
+    //reg_base is where the layout puts a, the rest follow it. Extra registers are at their own offset from it
+    pub fn reg_to_mem_location(&self, reg_value: reg, reg_base: u16) -> Option<u16> {
         let result = match reg_value {
-            reg if reg == self.a => 0x400,
-            reg if reg == self.b => 0x401,
-            reg if reg == self.c => 0x402,
-            reg if reg == self.d => 0x403,
-            reg if reg == self.s => 0x404,
-            reg if reg == self.i => 0x405,
-            reg if reg == self.f => 0x406,
+            reg if reg == self.a => reg_base,
+            reg if reg == self.b => reg_base.checked_add(1)?,
+            reg if reg == self.c => reg_base.checked_add(2)?,
+            reg if reg == self.d => reg_base.checked_add(3)?,
+            reg if reg == self.s => reg_base.checked_add(4)?,
+            reg if reg == self.i => reg_base.checked_add(5)?,
+            reg if reg == self.f => reg_base.checked_add(6)?,
             REG_NONE => 0xffff,
-            _ => return None,
+            _ => reg_base.checked_add(self.extra_register(reg_value)?.2)?,
         };
         Some(result)
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Instruction {
     Sys { num: u8, dst: reg }, // Calls syscall num and stores returned value in dst reg
-    Cmp { left: reg, right: reg }, // Compares left reg with right reg
-    Stk { pop: reg, push: reg }, // Stack
+    Cmp { left: reg, right: reg }, // Compares left reg with right reg. NONE counts as 0, so CMP x NONE tests x for zero
+    Stk { pop: reg, push: reg },   // Pushes push, then pops into pop. Either can be NONE
     Ldm { dst: reg, src: reg }, // Stores value from the memory location pointed by src to the dst reg
     Stm { dst: reg, src: reg }, // Store value from src reg to the memory location pointed by dst reg
     Imm { dst: reg, val: u8 },  // Load immediate value to register
     Jmp { flags: u8, dst: reg }, // Jmps to location stored in dst flag is current flags match flags value
     Add { dst: reg, src: reg },  // I wonder what this could be
+    Halt,                        // Stops the VM. Only decodes if the config has a halt opcode
 }
 
 //Best we can do without info of opcodes
@@ -167,10 +494,34 @@ impl fmt::Display for Instruction {
             Instruction::Add { dst, src } => {
                 write!(f, "ADD {{ dst: {}, src: {} }}", dst, src)
             }
+            Instruction::Halt => write!(f, "HALT"),
         }
     }
 }
 impl Instruction {
+    //Source line the assembler turns back into this instruction
+    pub fn to_assembly(&self, ctx: &VMConsts) -> String {
+        // Registers the config doesn't name are written as raw bytes, which the assembler takes back
+        let reg = |register: reg| match ctx.registers.reg_byte_to_str(register) {
+            "Unknown" => format!("r{:#04x}", register),
+            name => name.to_string(),
+        };
+        match *self {
+            Instruction::Sys { num, dst } => format!("SYS {} {}", num, reg(dst)),
+            Instruction::Cmp { left, right } => format!("CMP {} {}", reg(left), reg(right)),
+            Instruction::Stk { pop, push } => format!("STK {} {}", reg(pop), reg(push)),
+            Instruction::Ldm { dst, src } => format!("LDM {} {}", reg(dst), reg(src)),
+            Instruction::Stm { dst, src } => format!("STM {} {}", reg(dst), reg(src)),
+            Instruction::Imm { dst, val } => format!("IMM {} {}", reg(dst), val),
+            Instruction::Jmp { flags, dst } => format!("JMP {} {}", flags, reg(dst)),
+            Instruction::Add { dst, src } => match ctx.add_operand {
+                AddOperandMode::Register => format!("ADD {} {}", reg(dst), reg(src)),
+                AddOperandMode::Immediate => format!("ADD {} {}", reg(dst), src),
+            },
+            Instruction::Halt => "HALT".to_string(),
+        }
+    }
+
     pub fn pretty_print(&self, mapping: Registers) {
         match *self {
             Instruction::Sys { num, dst } => {
@@ -229,6 +580,7 @@ impl Instruction {
                     mapping.reg_byte_to_str(src)
                 )
             }
+            Instruction::Halt => println!("HALT"),
         }
     }
 }
@@ -240,6 +592,18 @@ pub struct InstructionDecodeIndices {
     pub right_param: usize,
 }
 
+impl InstructionDecodeIndices {
+    //Each index has to point at a different byte of the instruction, otherwise a byte gets read twice and one is never read
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut indices = [self.opcode, self.left_param, self.right_param];
+        indices.sort_unstable();
+        if indices != [0, 1, 2] {
+            return Err(ConfigError::InvalidDecodeIndices { indices: *self });
+        }
+        Ok(())
+    }
+}
+
 //Turns out that instruction are dynamic both on the opcodes and the locations of each one of the 3 parameters (opcode, left_param and right_param)
 impl Instruction {
     pub fn from_bytes(
@@ -288,7 +652,383 @@ impl Instruction {
                 dst: left_param,
                 src: right_param,
             }),
+            opcode if Some(opcode) == opcodes.halt => Some(Instruction::Halt),
             _ => None,
         }
     }
+
+    //The opcode byte this instruction encodes to. HALT is 0 on configs that don't have one
+    pub fn opcode(&self, opcodes: &InstructionOpcodes) -> u8 {
+        match self {
+            Instruction::Sys { .. } => opcodes.sys,
+            Instruction::Cmp { .. } => opcodes.cmp,
+            Instruction::Stk { .. } => opcodes.stk,
+            Instruction::Ldm { .. } => opcodes.ldm,
+            Instruction::Stm { .. } => opcodes.stm,
+            Instruction::Imm { .. } => opcodes.imm,
+            Instruction::Jmp { .. } => opcodes.jmp,
+            Instruction::Add { .. } => opcodes.add,
+            Instruction::Halt => opcodes.halt.unwrap_or(0),
+        }
+    }
+
+    //Inverse of from_bytes, each byte goes where the decoder reads it from. HALT encodes as opcode 0 if the config doesn't have one
+    pub fn to_bytes(
+        &self,
+        indices: InstructionDecodeIndices,
+        opcodes: InstructionOpcodes,
+    ) -> [u8; 3] {
+        let (left_param, right_param) = match *self {
+            Instruction::Sys { num, dst } => (num, dst),
+            Instruction::Cmp { left, right } => (left, right),
+            Instruction::Stk { pop, push } => (pop, push),
+            Instruction::Ldm { dst, src } => (dst, src),
+            Instruction::Stm { dst, src } => (dst, src),
+            Instruction::Imm { dst, val } => (dst, val),
+            Instruction::Jmp { flags, dst } => (flags, dst),
+            Instruction::Add { dst, src } => (dst, src),
+            Instruction::Halt => (0, 0),
+        };
+        let mut bytes = [0; 3];
+        bytes[indices.opcode] = self.opcode(&opcodes);
+        bytes[indices.left_param] = left_param;
+        bytes[indices.right_param] = right_param;
+        bytes
+    }
+
+    //Same as from_bytes, but when decoding fails it says why
+    pub fn explain_decode(
+        instruction_bytes: &[u8],
+        indices: InstructionDecodeIndices,
+        opcodes: InstructionOpcodes,
+    ) -> DecodeResult {
+        if instruction_bytes.len() != 3 {
+            return DecodeResult::WrongLength {
+                len: instruction_bytes.len(),
+            };
+        }
+        match Instruction::from_bytes(instruction_bytes, indices, opcodes) {
+            Some(instruction) => DecodeResult::Decoded(instruction),
+            None => {
+                let mut candidates = opcodes.all().to_vec();
+                if let Some(halt) = opcodes.halt {
+                    candidates.push((halt, "HALT"));
+                }
+                DecodeResult::UnknownOpcode {
+                    opcode: instruction_bytes[indices.opcode],
+                    candidates,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeResult {
+    Decoded(Instruction),
+    WrongLength {
+        len: usize,
+    },
+    UnknownOpcode {
+        opcode: u8,
+        candidates: Vec<(u8, &'static str)>, // Every opcode it was compared against
+    },
+}
+
+impl fmt::Display for DecodeResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeResult::Decoded(instruction) => write!(f, "{}", instruction),
+            DecodeResult::WrongLength { len } => {
+                write!(f, "Instructions are 3 bytes long, got {}", len)
+            }
+            DecodeResult::UnknownOpcode { opcode, candidates } => {
+                let candidates: Vec<String> = candidates
+                    .iter()
+                    .map(|(opcode, mnemonic)| format!("{} {:#04x}", mnemonic, opcode))
+                    .collect();
+                write!(
+                    f,
+                    "Opcode {:#04x} matches none of: {}",
+                    opcode,
+                    candidates.join(", ")
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::testing::consts;
+
+    #[test]
+    fn duplicated_decode_index_is_rejected() {
+        let indices = InstructionDecodeIndices {
+            opcode: 0,
+            left_param: 1,
+            right_param: 1,
+        };
+        assert!(matches!(
+            indices.validate(),
+            Err(ConfigError::InvalidDecodeIndices { .. })
+        ));
+        let mut ctx = consts();
+        ctx.instruction_indices = indices;
+        assert!(matches!(
+            ctx.validate(),
+            Err(ConfigError::InvalidDecodeIndices { .. })
+        ));
+        assert!(consts().instruction_indices.validate().is_ok());
+    }
+
+    #[test]
+    fn enabled_ops_skips_unset_and_shared_opcodes() {
+        assert_eq!(consts().enabled_ops().len(), 8);
+
+        let mut unset = consts();
+        unset.opcodes.sys = 0;
+        assert!(!unset.enabled_ops().contains(&"SYS"));
+
+        // A shared opcode makes both instructions ambiguous
+        let mut shared = consts();
+        shared.opcodes.jmp = shared.opcodes.add;
+        assert_eq!(
+            shared.enabled_ops(),
+            ["IMM", "STK", "STM", "LDM", "CMP", "SYS"]
+        );
+    }
+
+    #[test]
+    fn enabled_ops_lists_halt_only_when_set() {
+        assert!(!consts().enabled_ops().contains(&"HALT"));
+
+        let mut with_halt = consts();
+        with_halt.opcodes.halt = Some(0x3);
+        assert!(with_halt.enabled_ops().contains(&"HALT"));
+
+        let mut shared = consts();
+        shared.opcodes.halt = Some(shared.opcodes.add);
+        assert!(!shared.enabled_ops().contains(&"HALT"));
+        assert!(!shared.enabled_ops().contains(&"ADD"));
+    }
+
+    #[test]
+    fn opcode_table_matches_the_config() {
+        let ctx = consts();
+        let table = ctx.opcodes.all();
+        assert_eq!(
+            table,
+            [
+                (0x1, "IMM"),
+                (0x10, "ADD"),
+                (0x2, "STK"),
+                (0x8, "STM"),
+                (0x80, "LDM"),
+                (0x40, "CMP"),
+                (0x20, "JMP"),
+                (0x4, "SYS"),
+            ]
+        );
+        // Every pair decodes to the instruction it names
+        for (opcode, mnemonic) in table {
+            let instruction =
+                Instruction::from_bytes(&[opcode, 0, 0], ctx.instruction_indices, ctx.opcodes)
+                    .unwrap();
+            assert!(instruction.to_assembly(&ctx).starts_with(mnemonic));
+        }
+    }
+
+    #[test]
+    fn explain_decode_lists_every_opcode_it_tried() {
+        let ctx = consts();
+        let explained =
+            Instruction::explain_decode(&[0x03, 0, 0], ctx.instruction_indices, ctx.opcodes);
+        assert_eq!(
+            explained,
+            DecodeResult::UnknownOpcode {
+                opcode: 0x03,
+                candidates: ctx.opcodes.all().to_vec(),
+            }
+        );
+        let message = explained.to_string();
+        for (_, mnemonic) in ctx.opcodes.all() {
+            assert!(
+                message.contains(mnemonic),
+                "{} missing from {}",
+                mnemonic,
+                message
+            );
+        }
+        assert_eq!(
+            Instruction::explain_decode(&[0x01, 0x10, 5], ctx.instruction_indices, ctx.opcodes),
+            DecodeResult::Decoded(Instruction::Imm { dst: 0x10, val: 5 })
+        );
+        assert_eq!(
+            Instruction::explain_decode(&[0x01], ctx.instruction_indices, ctx.opcodes),
+            DecodeResult::WrongLength { len: 1 }
+        );
+    }
+
+    #[test]
+    fn from_bytes_inverts_to_bytes() {
+        let mut ctx = consts();
+        ctx.opcodes.halt = Some(0x3);
+        let permutations = [
+            (0, 1, 2),
+            (0, 2, 1),
+            (1, 0, 2),
+            (1, 2, 0),
+            (2, 0, 1),
+            (2, 1, 0),
+        ];
+        for (opcode, left_param, right_param) in permutations {
+            let indices = InstructionDecodeIndices {
+                opcode,
+                left_param,
+                right_param,
+            };
+            for (x, y) in [(0, 0), (0x10, 0x08), (0xff, 0x01), (0x80, 0x7f)] {
+                let instructions = [
+                    Instruction::Imm { dst: x, val: y },
+                    Instruction::Add { dst: x, src: y },
+                    Instruction::Stk { pop: x, push: y },
+                    Instruction::Stm { dst: x, src: y },
+                    Instruction::Ldm { dst: x, src: y },
+                    Instruction::Cmp { left: x, right: y },
+                    Instruction::Jmp { flags: x, dst: y },
+                    Instruction::Sys { num: x, dst: y },
+                ];
+                for instruction in instructions {
+                    let bytes = instruction.to_bytes(indices, ctx.opcodes);
+                    assert_eq!(
+                        Instruction::from_bytes(&bytes, indices, ctx.opcodes),
+                        Some(instruction)
+                    );
+                }
+            }
+            // HALT has no operands to vary
+            let bytes = Instruction::Halt.to_bytes(indices, ctx.opcodes);
+            assert_eq!(
+                Instruction::from_bytes(&bytes, indices, ctx.opcodes),
+                Some(Instruction::Halt)
+            );
+        }
+    }
+
+    #[test]
+    fn default_layout_matches_the_original_vm() {
+        let layout = consts().layout();
+        assert_eq!(
+            layout,
+            MemoryLayout {
+                ram_base: 0x300,
+                ram_size: 0x100,
+                reg_base: 0x400,
+            }
+        );
+        assert_eq!(layout.code(), 0..0x300);
+        assert_eq!(layout.ram(), 0x300..0x400);
+        assert_eq!(layout.registers(), 0x400..0x407);
+    }
+
+    #[test]
+    fn layout_past_the_address_space_is_rejected() {
+        for layout in [
+            MemoryLayout {
+                ram_base: 0xff80,
+                ram_size: 0x100,
+                reg_base: 0x400,
+            },
+            MemoryLayout {
+                ram_base: 0x300,
+                ram_size: 0x100,
+                reg_base: 0xfffc,
+            },
+        ] {
+            let mut ctx = consts();
+            ctx.memory_layout = Some(layout);
+            assert!(matches!(
+                ctx.validate(),
+                Err(ConfigError::InvalidMemoryLayout { .. })
+            ));
+            // The ranges still come out, cut at the end of the address space
+            assert_eq!(layout.ram().end.max(layout.registers().end), 0xffff);
+        }
+    }
+
+    #[test]
+    fn extra_registers_follow_reg_base() {
+        let mut ctx = consts();
+        ctx.registers.extra = &[("e", 0x03, 7)];
+        ctx.memory_layout = Some(MemoryLayout {
+            ram_base: 0x100,
+            ram_size: 0x100,
+            reg_base: 0x200,
+        });
+        assert!(ctx.validate().is_ok());
+        assert_eq!(
+            ctx.registers
+                .reg_to_mem_location(0x03, ctx.layout().reg_base),
+            Some(0x207)
+        );
+
+        // Past the end of memory, on top of f, or inside the data page
+        let cases: [(&'static [ExtraRegister], u16); 3] = [
+            (&[("e", 0x03, 0xfffe)], 0x200),
+            (&[("e", 0x03, 6)], 0x200),
+            (&[("e", 0x03, 7)], 0xf9),
+        ];
+        for (extra, reg_base) in cases {
+            let mut ctx = ctx;
+            ctx.registers.extra = extra;
+            ctx.memory_layout = Some(MemoryLayout {
+                reg_base,
+                ..ctx.layout()
+            });
+            assert!(matches!(
+                ctx.validate(),
+                Err(ConfigError::InvalidExtraRegister { name: "e" })
+            ));
+        }
+    }
+
+    #[test]
+    fn opcode_matches_the_encoded_byte() {
+        let ctx = consts();
+        let (a, b) = (ctx.registers.a, ctx.registers.b);
+        let add = Instruction::Add { dst: a, src: b };
+        assert_eq!(add.opcode(&ctx.opcodes), ctx.opcodes.add);
+        let bytes = add.to_bytes(ctx.instruction_indices, ctx.opcodes);
+        assert_eq!(
+            bytes[ctx.instruction_indices.opcode],
+            add.opcode(&ctx.opcodes)
+        );
+        assert_eq!(Instruction::Halt.opcode(&ctx.opcodes), 0);
+    }
+
+    #[test]
+    fn self_check_catches_a_shared_opcode() {
+        assert!(consts().self_check().is_ok());
+        let mut ctx = consts();
+        ctx.opcodes.stm = ctx.opcodes.ldm;
+        assert!(matches!(
+            ctx.self_check(),
+            Err(ConfigError::EncodingMismatch {
+                instruction: Instruction::Stm { .. },
+                decoded: Some(Instruction::Ldm { .. })
+            })
+        ));
+    }
+
+    #[test]
+    fn self_check_rejects_out_of_range_indices() {
+        let mut ctx = consts();
+        ctx.instruction_indices.opcode = 3;
+        assert!(matches!(
+            ctx.self_check(),
+            Err(ConfigError::InvalidDecodeIndices { .. })
+        ));
+    }
 }
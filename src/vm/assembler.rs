@@ -1,6 +1,13 @@
-use std::{error, fmt};
+use std::{
+    collections::{HashMap, HashSet},
+    error, fmt, fs, io,
+    path::Path,
+};
 
-use super::arch::{Registers, VMConsts};
+use super::{
+    analysis::constant_jump_targets,
+    arch::{reg, AddOperandMode, CmpFlags, ConfigError, Instruction, Registers, VMConsts},
+};
 
 #[derive(Clone, Debug)]
 pub enum InvalidInstruction {
@@ -8,6 +15,18 @@ pub enum InvalidInstruction {
     InvalidOperation { operation: String, line: usize },
     InvalidNumber { number: String, line: usize },
     InvalidNumberOfParts { lines: usize, line: usize },
+    InvalidConfig { error: ConfigError },
+    InvalidDirective { directive: String, line: usize },
+    UndefinedConstant { name: String, line: usize },
+    UnmatchedConditional { line: usize },
+    DuplicateLabel { label: String, line: usize },
+    UnmatchedMacro { line: usize },
+    RecursiveMacro { name: String, line: usize },
+    UndefinedLabel { label: String, line: usize },
+    InvalidJumpTarget { target: u8, line: usize },
+    InvalidJumpFlags { flags: String, line: usize },
+    ProgramTooLarge { line: usize },
+    ForwardReference { label: String, line: usize },
 }
 
 impl error::Error for InvalidInstruction {}
@@ -31,14 +50,79 @@ impl fmt::Display for InvalidInstruction {
             InvalidInstruction::InvalidNumber { number, line } => {
                 write!(f, "Invalid Number at line {}: {}", line, number)
             }
+            InvalidInstruction::InvalidConfig { error } => {
+                write!(f, "Can't assemble with this config: {}", error)
+            }
+            InvalidInstruction::InvalidDirective { directive, line } => {
+                write!(f, "Invalid directive at line {}: {}", line, directive)
+            }
+            InvalidInstruction::UndefinedConstant { name, line } => {
+                write!(f, "Undefined constant at line {}: {}", line, name)
+            }
+            InvalidInstruction::UnmatchedConditional { line } => {
+                write!(f, "Unmatched .if/.endif at line {}", line)
+            }
+            InvalidInstruction::DuplicateLabel { label, line } => {
+                write!(f, "Label defined twice at line {}: {}", line, label)
+            }
+            InvalidInstruction::UnmatchedMacro { line } => {
+                write!(f, "Unmatched .macro/.endm at line {}", line)
+            }
+            InvalidInstruction::RecursiveMacro { name, line } => {
+                write!(f, "Macro expands into itself at line {}: {}", line, name)
+            }
+            InvalidInstruction::UndefinedLabel { label, line } => {
+                write!(f, "Undefined label at line {}: {}", line, label)
+            }
+            InvalidInstruction::InvalidJumpFlags { flags, line } => {
+                write!(f, "Invalid JMP flags at line {}: {}", line, flags)
+            }
+            InvalidInstruction::InvalidJumpTarget { target, line } => {
+                write!(
+                    f,
+                    "JMP at line {} targets ip {}, which is inside data rather than an instruction",
+                    line, target
+                )
+            }
+            InvalidInstruction::ProgramTooLarge { line } => {
+                write!(f, "Program is past the last ip (255) at line {}", line)
+            }
+            InvalidInstruction::ForwardReference { label, line } => {
+                write!(
+                    f,
+                    "Label used before it's defined at line {}, where the size has to be known: {}",
+                    line, label
+                )
+            }
         }
     }
 }
 
+//ip of the instruction that starts at the next 3 byte boundary after len bytes
+fn next_ip(len: usize, line: usize) -> Result<u8, InvalidInstruction> {
+    u8::try_from(len.div_ceil(3)).map_err(|_| InvalidInstruction::ProgramTooLarge { line })
+}
+
+//Decimal, 0x hex or 0b binary. Tokens are trimmed in case a stray \r from mixed line endings made it this far.
+//Single underscores between digits are ignored, so 0b1010_1010 works
 fn parse_num(num: &str, line: usize) -> Result<u8, InvalidInstruction> {
-    match num.parse::<u8>() {
-        Ok(parsed) => Ok(parsed), //Maybe also check if it's a valid number in the context of syscalls
-        Err(_) => Err(InvalidInstruction::InvalidNumber {
+    let trimmed = num.trim();
+    let (digits, radix) = if let Some(hex) = trimmed.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(binary) = trimmed.strip_prefix("0b") {
+        (binary, 2)
+    } else {
+        (trimmed, 10)
+    };
+    let separators_ok =
+        !digits.starts_with('_') && !digits.ends_with('_') && !digits.contains("__");
+    let parsed = match separators_ok {
+        true => u8::from_str_radix(&digits.replace('_', ""), radix).ok(),
+        false => None,
+    };
+    match parsed {
+        Some(parsed) => Ok(parsed), //Maybe also check if it's a valid number in the context of syscalls
+        None => Err(InvalidInstruction::InvalidNumber {
             number: num.to_string(),
             line,
         }),
@@ -46,7 +130,7 @@ fn parse_num(num: &str, line: usize) -> Result<u8, InvalidInstruction> {
 }
 
 fn parse_reg(reg: &str, registers: &Registers, line: usize) -> Result<u8, InvalidInstruction> {
-    match registers.reg_str_to_byte(reg) {
+    match registers.reg_str_to_byte(reg.trim()) {
         Some(res) => Ok(res),
         None => Err(InvalidInstruction::InvalidRegister {
             register: reg.to_string(),
@@ -55,67 +139,1525 @@ fn parse_reg(reg: &str, registers: &Registers, line: usize) -> Result<u8, Invali
     }
 }
 
+//A register by its byte instead of its name, as r0x20 or r32. For registers that haven't been identified yet
+fn parse_raw_reg(reg: &str) -> Option<u8> {
+    let byte = reg.strip_prefix('r')?;
+    match byte.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => byte.parse::<u8>().ok(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AsmStats {
+    pub instructions: usize,
+    pub bytes: usize,
+}
+
+impl fmt::Display for AsmStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "assembled {} instructions ({} bytes)",
+            self.instructions, self.bytes
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum AssembleFileError {
+    Assembly(InvalidInstruction),
+    Io(io::Error),
+}
+
+impl error::Error for AssembleFileError {}
+
+impl fmt::Display for AssembleFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssembleFileError::Assembly(err) => write!(f, "{}", err),
+            AssembleFileError::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl From<InvalidInstruction> for AssembleFileError {
+    fn from(err: InvalidInstruction) -> Self {
+        AssembleFileError::Assembly(err)
+    }
+}
+
+impl From<io::Error> for AssembleFileError {
+    fn from(err: io::Error) -> Self {
+        AssembleFileError::Io(err)
+    }
+}
+
+pub fn write_bytecode(path: impl AsRef<Path>, program: &[u8]) -> io::Result<()> {
+    fs::write(path, program)
+}
+
+pub fn read_bytecode(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+    fs::read(path)
+}
+
+pub fn assemble_to_file(
+    code: String,
+    ctx: VMConsts,
+    path: impl AsRef<Path>,
+) -> Result<(), AssembleFileError> {
+    let program = assemble(code, ctx)?;
+    write_bytecode(path, &program)?;
+    Ok(())
+}
+
+pub type IpMap = Vec<(usize, u8)>; // (line, ip) for every instruction
+
+//Everything a single assembler pass produces, the public functions pick what they need out of it
+struct Assembly {
+    program: Vec<u8>,
+    entry: u8,
+    instructions: usize,
+    warnings: Vec<AsmWarning>,
+    labels: HashMap<String, u8>,
+    ip_map: IpMap,
+}
+
 pub fn assemble(code: String, ctx: VMConsts) -> Result<Vec<u8>, InvalidInstruction> {
-    let mut result: Vec<u8> = vec![];
+    assemble_lines(code.lines(), ctx)
+}
+
+//Same as assemble, but keeps going after a bad line and returns every error, in line order
+pub fn assemble_all(code: String, ctx: VMConsts) -> Result<Vec<u8>, Vec<InvalidInstruction>> {
+    assemble_program_all(code.lines(), ctx, false).map(|assembly| assembly.program)
+}
+
+//Same as assemble, but takes the source a line at a time so it doesn't have to be in one String first.
+//Labels can point forward, so lines are still kept (macro-expanded) until the end for the second pass
+pub fn assemble_lines<'a>(
+    lines: impl Iterator<Item = &'a str>,
+    ctx: VMConsts,
+) -> Result<Vec<u8>, InvalidInstruction> {
+    assemble_program(lines, ctx, false).map(|assembly| assembly.program)
+}
+
+//Same as assemble, but also returns the ip set by the .entry directive (0 if there isn't one) so the loader can start there
+pub fn assemble_with_entry(
+    code: String,
+    ctx: VMConsts,
+) -> Result<(Vec<u8>, u8), InvalidInstruction> {
+    assemble_program(code.lines(), ctx, false).map(|assembly| (assembly.program, assembly.entry))
+}
+
+pub fn assemble_with_stats(
+    code: String,
+    ctx: VMConsts,
+) -> Result<(Vec<u8>, AsmStats), InvalidInstruction> {
+    assemble_program(code.lines(), ctx, false).map(|assembly| {
+        let stats = AsmStats {
+            instructions: assembly.instructions,
+            bytes: assembly.program.len(),
+        };
+        (assembly.program, stats)
+    })
+}
+
+//Same as assemble, plus warnings about code that assembles but probably doesn't do what was meant
+pub fn assemble_with_warnings(
+    code: String,
+    ctx: VMConsts,
+) -> Result<(Vec<u8>, Vec<AsmWarning>), InvalidInstruction> {
+    assemble_program(code.lines(), ctx, false).map(|assembly| (assembly.program, assembly.warnings))
+}
+
+//Everything before the first ; or # that isn't inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' | '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+//Whitespace separated tokens, except a "quoted string" is a single token, quotes included
+fn tokenize(code: &str) -> Vec<&str> {
+    let mut tokens = vec![];
+    let mut start = None;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in code.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => {
+                quoted = !quoted;
+                start.get_or_insert(i);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if let Some(start) = start.take() {
+                    tokens.push(&code[start..i]);
+                }
+            }
+            _ => {
+                start.get_or_insert(i);
+            }
+        }
+    }
+    if let Some(start) = start {
+        tokens.push(&code[start..]);
+    }
+    tokens
+}
+
+//Bytes of a "quoted string" token. Supports the \n, \t, \0, \\ and \" escapes
+fn parse_string(token: &str, line: usize) -> Result<Vec<u8>, InvalidInstruction> {
+    let invalid = || InvalidInstruction::InvalidDirective {
+        directive: token.to_string(),
+        line,
+    };
+    let text = token
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+    let mut bytes = vec![];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '"')) => c,
+                _ => return Err(invalid()),
+            },
+            c => c,
+        };
+        bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    }
+    Ok(bytes)
+}
+
+//Splits a line into its label, if it has one, and the code after it, dropping any comment
+fn split_line(line: &str) -> (Option<&str>, &str) {
+    let code = strip_comment(line).trim();
+    let (first, rest) = code.split_once(char::is_whitespace).unwrap_or((code, ""));
+    match first.strip_suffix(':') {
+        Some(label) if !label.is_empty() => (Some(label), rest.trim()),
+        _ => (None, code),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Register(reg),
+    Value(String), // Number, .equ constant or label. Resolved when the line is assembled, once every symbol is known
+}
+
+//A single line of source, checked but not yet turned into bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParsedLine {
+    Instruction {
+        label: Option<String>,
+        mnemonic: String,
+        operands: Vec<Operand>,
+    },
+    Directive {
+        label: Option<String>,
+        name: String, // Including the leading .
+        args: Vec<String>,
+    },
+    Label(String), // A label with nothing after it, it points at whatever comes next
+    Blank,
+}
+
+pub fn parse_line(
+    line: &str,
+    line_no: usize,
+    ctx: &VMConsts,
+) -> Result<ParsedLine, InvalidInstruction> {
+    parse_line_with(line, line_no, ctx, false)
+}
+
+//Strict rejects raw register bytes, so every register has to be one the config knows about
+fn parse_line_with(
+    line: &str,
+    line_no: usize,
+    ctx: &VMConsts,
+    strict: bool,
+) -> Result<ParsedLine, InvalidInstruction> {
+    let (label, code) = split_line(line);
+    let label = label.map(str::to_string);
+    let parts = tokenize(code);
+    let Some(first) = parts.first() else {
+        return Ok(match label {
+            Some(label) => ParsedLine::Label(label),
+            None => ParsedLine::Blank,
+        });
+    };
+    if first.starts_with('.') {
+        return Ok(ParsedLine::Directive {
+            label,
+            name: first.to_ascii_lowercase(),
+            args: parts[1..].iter().map(|arg| arg.to_string()).collect(),
+        });
+    }
+    // Mnemonics and directives aren't case sensitive, labels and constants are
+    let op = parts[0].to_ascii_uppercase();
 
-    for (i, line) in code.lines().enumerate() {
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() != 3 {
+    let register = |token: &str| {
+        parse_reg(token, &ctx.registers, line_no)
+            .or_else(|err| match parse_raw_reg(token) {
+                Some(register) if !strict => Ok(register),
+                _ => Err(err),
+            })
+            .map(Operand::Register)
+    };
+    let value = |token: &str| Operand::Value(token.to_string());
+
+    let operands = match (op.as_str(), &parts[1..]) {
+        ("SYS", [num, dst]) => vec![value(num), register(dst)?],
+        ("CMP" | "STK" | "LDM" | "STM", [left, right]) => vec![register(left)?, register(right)?],
+        ("IMM", [dst, val]) => vec![register(dst)?, value(val)],
+        // flags is a mask of the cmp_flags to jump on (see Symbols::jump_flags), 0 jumps unconditionally.
+        // dst can also be a label or ip, which gets loaded into the scratch register first
+        ("JMP", [flags, dst]) => vec![value(flags), register(dst).unwrap_or_else(|_| value(dst))],
+        ("ADD", [dst, src]) => match ctx.add_operand {
+            AddOperandMode::Register => vec![register(dst)?, register(src)?],
+            AddOperandMode::Immediate => vec![register(dst)?, value(src)],
+        },
+        // Only exists on configs that give it an opcode
+        ("HALT", []) if ctx.opcodes.halt.is_some() => vec![],
+        ("SYS" | "CMP" | "STK" | "LDM" | "STM" | "IMM" | "JMP" | "ADD", _) => {
             return Err(InvalidInstruction::InvalidNumberOfParts {
                 lines: parts.len(),
-                line: i,
-            });
-        }
-        let op = parts[0];
-        let left = parts[1];
-        let right = parts[2];
-
-        //TODO: Fix ordering based on ctx
-        match op {
-            "SYS" => {
-                result.push(ctx.opcodes.sys);
-                result.push(parse_num(left, i)?);
-                result.push(parse_reg(right, &ctx.registers, i)?)
-            }
-            "CMP" => {
-                result.push(ctx.opcodes.cmp);
-                result.push(parse_reg(left, &ctx.registers, i)?);
-                result.push(parse_reg(right, &ctx.registers, i)?)
-            }
-            "STK" => {
-                result.push(ctx.opcodes.stk);
-                result.push(parse_reg(left, &ctx.registers, i)?);
-                result.push(parse_reg(right, &ctx.registers, i)?)
-            }
-            "LDM" => {
-                result.push(ctx.opcodes.ldm);
-                result.push(parse_reg(left, &ctx.registers, i)?);
-                result.push(parse_reg(right, &ctx.registers, i)?)
-            }
-            "STM" => {
-                result.push(ctx.opcodes.stm);
-                result.push(parse_reg(left, &ctx.registers, i)?);
-                result.push(parse_reg(right, &ctx.registers, i)?)
-            }
-            "IMM" => {
-                result.push(ctx.opcodes.imm);
-                result.push(parse_reg(left, &ctx.registers, i)?);
-                result.push(parse_num(right, i)?)
-            }
-            "JMP" => todo!(),
-            "ADD" => {
-                result.push(ctx.opcodes.add);
-                result.push(parse_reg(left, &ctx.registers, i)?);
-                result.push(parse_num(right, i)?)
+                line: line_no,
+            })
+        }
+        _ => {
+            return Err(InvalidInstruction::InvalidOperation {
+                operation: parts[0].to_string(),
+                line: line_no,
+            })
+        }
+    };
+    Ok(ParsedLine::Instruction {
+        label,
+        mnemonic: op,
+        operands,
+    })
+}
+
+//Names a numeric operand can use instead of a literal
+struct Symbols<'a> {
+    constants: HashMap<String, u8>,
+    labels: Option<&'a HashMap<String, u8>>, // None on the first pass, before label addresses are known
+}
+
+impl Symbols<'_> {
+    fn value(&self, token: &str, line: usize) -> Result<u8, InvalidInstruction> {
+        let token = token.trim();
+        parse_num(token, line).or_else(|err| {
+            if let Some(value) = self.constants.get(token) {
+                return Ok(*value);
+            }
+            match self.labels {
+                // Anything that doesn't start like a number was meant to be a name
+                Some(labels) => labels.get(token).copied().ok_or_else(|| {
+                    if token.starts_with(|c: char| c.is_ascii_digit()) {
+                        err
+                    } else {
+                        InvalidInstruction::UndefinedLabel {
+                            label: token.to_string(),
+                            line,
+                        }
+                    }
+                }),
+                None => Ok(0), // Placeholder, the second pass reports it if it's still unknown
+            }
+        })
+    }
+
+    //Same as value, but a label only resolves if it was defined above. Whatever decides how many bytes get emitted
+    //has to come out the same on both passes, and the first one doesn't know where later labels are yet
+    fn size(
+        &self,
+        token: &str,
+        defined: &HashMap<String, u8>,
+        line: usize,
+    ) -> Result<u8, InvalidInstruction> {
+        let token = token.trim();
+        let is_name = parse_num(token, line).is_err() && !self.constants.contains_key(token);
+        match defined.get(token) {
+            Some(ip) if is_name => Ok(*ip),
+            None if is_name && self.labels.is_some_and(|labels| labels.contains_key(token)) => {
+                Err(InvalidInstruction::ForwardReference {
+                    label: token.to_string(),
+                    line,
+                })
             }
+            _ => self.value(token, line),
+        }
+    }
+
+    //Z, L, G, E and N for the cmp flags, joined with | to jump on any of them. 0 or ALWAYS jumps unconditionally.
+    //Numbers and constants still work for masks that don't have a name
+    fn jump_flags(
+        &self,
+        token: &str,
+        cmp: &CmpFlags,
+        line: usize,
+    ) -> Result<u8, InvalidInstruction> {
+        token.split('|').try_fold(0, |mask, part| {
+            let flag = match part.trim().to_ascii_uppercase().as_str() {
+                "Z" => cmp.zero,
+                "L" => cmp.smaller,
+                "G" => cmp.bigger,
+                "E" => cmp.equals,
+                "N" => cmp.not_equals,
+                "ALWAYS" => 0,
+                _ => match parse_num(part, line) {
+                    Ok(value) => value,
+                    Err(_) => *self.constants.get(part.trim()).ok_or_else(|| {
+                        InvalidInstruction::InvalidJumpFlags {
+                            flags: token.to_string(),
+                            line,
+                        }
+                    })?,
+                },
+            };
+            Ok(mask | flag)
+        })
+    }
+
+    fn operand(&self, operand: &Operand, line: usize) -> Result<u8, InvalidInstruction> {
+        match operand {
+            Operand::Register(register) => Ok(*register),
+            Operand::Value(token) => self.value(token, line),
+        }
+    }
+}
+
+//Same as assemble, plus the ip each instruction ended up at as (line, ip) pairs.
+//Data directives take up bytes too, so an instruction's ip isn't necessarily how many instructions came before it
+pub fn assemble_with_ip_map(
+    code: String,
+    ctx: VMConsts,
+) -> Result<(Vec<u8>, IpMap), InvalidInstruction> {
+    assemble_program(code.lines(), ctx, false).map(|assembly| (assembly.program, assembly.ip_map))
+}
+
+//Same as assemble, but registers have to be referred to by name
+pub fn assemble_strict(code: String, ctx: VMConsts) -> Result<Vec<u8>, InvalidInstruction> {
+    assemble_program(code.lines(), ctx, true).map(|assembly| assembly.program)
+}
 
+//Only the first error, for the functions that predate assemble_all
+fn assemble_program<'a>(
+    code: impl Iterator<Item = &'a str>,
+    ctx: VMConsts,
+    strict: bool,
+) -> Result<Assembly, InvalidInstruction> {
+    assemble_program_all(code, ctx, strict).map_err(|errors| errors[0].clone())
+}
+
+fn assemble_program_all<'a>(
+    code: impl Iterator<Item = &'a str>,
+    ctx: VMConsts,
+    strict: bool,
+) -> Result<Assembly, Vec<InvalidInstruction>> {
+    // Expansions like JMP to a label clobber the scratch register, so it has to be a safe one
+    ctx.validate()
+        .map_err(|error| vec![InvalidInstruction::InvalidConfig { error }])?;
+    let lines = expand_macros(code).map_err(|err| vec![err])?;
+    // The first pass only finds out where labels are so the second one can resolve references to them.
+    // Any errors it finds are on lines the second pass reports too
+    let labels = assemble_pass(&lines, ctx, strict, None).0.labels;
+    match assemble_pass(&lines, ctx, strict, Some(&labels)) {
+        (assembly, errors) if errors.is_empty() => Ok(assembly),
+        (_, errors) => Err(errors),
+    }
+}
+
+//Takes out .macro NAME ... .endm definitions and replaces every use of NAME with the body, \1, \2, ...
+//being the arguments it was used with. Lines keep the number of the line they came from, for errors
+fn expand_macros<'a>(
+    code: impl Iterator<Item = &'a str>,
+) -> Result<Vec<(usize, String)>, InvalidInstruction> {
+    let mut macros: HashMap<String, Vec<String>> = HashMap::new();
+    let mut defining: Option<(String, Vec<String>, usize)> = None; // (name, body, line of the .macro)
+    let mut lines = vec![];
+    for (i, line) in code.enumerate() {
+        let (_, code) = split_line(line);
+        let parts = tokenize(code);
+        let directive = parts.first().map(|first| first.to_ascii_lowercase());
+        match (directive.as_deref(), parts.len(), &mut defining) {
+            (Some(".endm"), 1, Some(_)) => {
+                if let Some((name, body, _)) = defining.take() {
+                    macros.insert(name, body);
+                }
+            }
+            (Some(".endm"), 1, None) => return Err(InvalidInstruction::UnmatchedMacro { line: i }),
+            (Some(".macro"), 2, None) => defining = Some((parts[1].to_string(), vec![], i)),
+            // Definitions can't be nested
+            (Some(".macro"), _, _) => {
+                return Err(InvalidInstruction::InvalidDirective {
+                    directive: code.to_string(),
+                    line: i,
+                })
+            }
+            (_, _, Some((_, body, _))) => body.push(line.to_string()),
+            (_, _, None) => lines.push((i, line.to_string())),
+        }
+    }
+    if let Some((_, _, line)) = defining {
+        return Err(InvalidInstruction::UnmatchedMacro { line });
+    }
+
+    let mut expanded = vec![];
+    for (i, line) in lines {
+        expand_line(&macros, i, &line, &mut vec![], &mut expanded)?;
+    }
+    Ok(expanded)
+}
+
+fn expand_line(
+    macros: &HashMap<String, Vec<String>>,
+    line_no: usize,
+    line: &str,
+    expanding: &mut Vec<String>, // Macros whose body is being expanded, innermost last
+    expanded: &mut Vec<(usize, String)>,
+) -> Result<(), InvalidInstruction> {
+    let (label, code) = split_line(line);
+    let parts = tokenize(code);
+    let Some((name, body)) = parts.first().and_then(|name| macros.get_key_value(*name)) else {
+        expanded.push((line_no, line.to_string()));
+        return Ok(());
+    };
+    if expanding.contains(name) {
+        return Err(InvalidInstruction::RecursiveMacro {
+            name: name.to_string(),
+            line: line_no,
+        });
+    }
+    // A label in front of the macro points at its first instruction
+    if let Some(label) = label {
+        expanded.push((line_no, format!("{}:", label)));
+    }
+    expanding.push(name.to_string());
+    for body_line in body {
+        let body_line = substitute_args(body_line, &parts[1..]);
+        expand_line(macros, line_no, &body_line, expanding, expanded)?;
+    }
+    expanding.pop();
+    Ok(())
+}
+
+//Replaces \1, \2, ... with the matching argument, or nothing if there aren't that many
+fn substitute_args(line: &str, args: &[&str]) -> String {
+    let mut result = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find('\\') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        match after[..digits].parse::<usize>() {
+            // \0 isn't an argument, it's the string escape
+            Ok(n) if n > 0 => result.push_str(args.get(n - 1).copied().unwrap_or_default()),
+            _ => result.push_str(&rest[start..start + 1 + digits]),
+        }
+        rest = &after[digits..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn assemble_pass(
+    lines: &[(usize, String)],
+    ctx: VMConsts,
+    strict: bool,
+    labels: Option<&HashMap<String, u8>>,
+) -> (Assembly, Vec<InvalidInstruction>) {
+    let mut result: Vec<u8> = vec![];
+    let mut entry: u8 = 0;
+    let mut instructions = 0;
+    let mut warnings: Vec<AsmWarning> = vec![];
+    let mut ip_map: IpMap = vec![];
+    let mut defined_labels: HashMap<String, u8> = HashMap::new();
+    let mut symbols = Symbols {
+        constants: HashMap::new(),
+        labels,
+    };
+    let mut conditions: Vec<(bool, usize)> = vec![]; // Open .if blocks as (condition, line)
+    let mut jumps: Vec<(usize, u8)> = vec![]; // (line, target) of JMPs to a label or ip
+    let mut instruction_ips: HashSet<u8> = HashSet::new();
+
+    let mut errors = vec![];
+    let mut assemble_line = |i: usize, line: &str| -> Result<(), InvalidInstruction> {
+        // Lines are only assembled if every enclosing .if is true
+        let active = conditions.iter().all(|(condition, _)| *condition);
+        let parsed = match parse_line_with(line, i, &ctx, strict) {
+            Ok(parsed) => parsed,
+            // Skipped lines don't have to be valid
+            Err(_) if !active => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let label = match &parsed {
+            ParsedLine::Instruction { label, .. } | ParsedLine::Directive { label, .. } => {
+                label.as_deref()
+            }
+            ParsedLine::Label(label) => Some(label.as_str()),
+            ParsedLine::Blank => None,
+        };
+        if let (Some(label), true) = (label, active) {
+            // Labels point at the instruction that comes after them, which starts on the next 3 byte boundary
+            let ip = next_ip(result.len(), i)?;
+            if defined_labels.insert(label.to_string(), ip).is_some() {
+                return Err(InvalidInstruction::DuplicateLabel {
+                    label: label.to_string(),
+                    line: i,
+                });
+            }
+        }
+        let (mnemonic, operands) = match &parsed {
+            ParsedLine::Instruction {
+                mnemonic, operands, ..
+            } => (mnemonic.as_str(), operands),
+            // Directives don't emit bytes
+            ParsedLine::Directive { name, args, .. } => {
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                match (name.as_str(), args.as_slice()) {
+                    // Skipped blocks still track nesting so their .endif isn't taken for ours
+                    (".if", [name]) => {
+                        let constant = symbols.constants.get(*name).copied();
+                        conditions.push((active && constant.unwrap_or_default() != 0, i));
+                        // The block is still tracked so its .endif doesn't show up as a second error
+                        if active && constant.is_none() {
+                            return Err(InvalidInstruction::UndefinedConstant {
+                                name: name.to_string(),
+                                line: i,
+                            });
+                        }
+                    }
+                    (".endif", []) => {
+                        conditions
+                            .pop()
+                            .ok_or(InvalidInstruction::UnmatchedConditional { line: i })?;
+                    }
+                    _ if !active => {}
+                    (".equ", [name, value]) => {
+                        symbols
+                            .constants
+                            .insert(name.to_string(), parse_num(value, i)?);
+                    }
+                    (".entry", [ip]) => entry = symbols.value(ip, i)?,
+                    // Reserves zeroed bytes. An instruction after it gets padded to start on a 3 byte boundary
+                    (".space", [size]) => {
+                        let size = symbols.size(size, &defined_labels, i)?;
+                        result.resize(result.len() + size as usize, 0);
+                    }
+                    // Null terminated, the way read_string expects it
+                    (".string", [text]) => {
+                        result.extend(parse_string(text, i)?);
+                        result.push(0);
+                    }
+                    _ => {
+                        return Err(InvalidInstruction::InvalidDirective {
+                            directive: split_line(line).1.to_string(),
+                            line: i,
+                        })
+                    }
+                }
+                return Ok(());
+            }
+            ParsedLine::Label(_) | ParsedLine::Blank => return Ok(()),
+        };
+        if !active {
+            return Ok(());
+        }
+        // JMP to a label or ip: IMM scratch target; JMP flags scratch
+        let (mut expansion, operands) = match (mnemonic, operands.as_slice()) {
+            ("JMP", [Operand::Value(flags), target]) => {
+                let flags = symbols.jump_flags(flags, &ctx.cmp_flags, i)?;
+                match target {
+                    Operand::Register(dst) => (vec![], vec![flags, *dst]),
+                    Operand::Value(_) => {
+                        let scratch = ctx.scratch_register();
+                        let target = symbols.operand(target, i)?;
+                        jumps.push((i, target));
+                        let imm = Instruction::Imm {
+                            dst: scratch,
+                            val: target,
+                        };
+                        (vec![imm], vec![flags, scratch])
+                    }
+                }
+            }
+            _ => (
+                vec![],
+                operands
+                    .iter()
+                    .map(|operand| symbols.operand(operand, i))
+                    .collect::<Result<Vec<u8>, InvalidInstruction>>()?,
+            ),
+        };
+
+        let instruction = match (mnemonic, operands.as_slice()) {
+            ("SYS", [num, dst]) => Instruction::Sys {
+                num: *num,
+                dst: *dst,
+            },
+            ("CMP", [left, right]) => Instruction::Cmp {
+                left: *left,
+                right: *right,
+            },
+            ("STK", [pop, push]) => Instruction::Stk {
+                pop: *pop,
+                push: *push,
+            },
+            ("LDM", [dst, src]) => Instruction::Ldm {
+                dst: *dst,
+                src: *src,
+            },
+            ("STM", [dst, src]) => Instruction::Stm {
+                dst: *dst,
+                src: *src,
+            },
+            ("IMM", [dst, val]) => Instruction::Imm {
+                dst: *dst,
+                val: *val,
+            },
+            ("JMP", [flags, dst]) => Instruction::Jmp {
+                flags: *flags,
+                dst: *dst,
+            },
+            ("ADD", [dst, src]) => Instruction::Add {
+                dst: *dst,
+                src: *src,
+            },
+            ("HALT", []) => Instruction::Halt,
+            // parse_line only lets known mnemonics with the right operands through
             _ => {
                 return Err(InvalidInstruction::InvalidOperation {
-                    operation: op.to_string(),
+                    operation: mnemonic.to_string(),
+                    line: i,
+                })
+            }
+        };
+
+        expansion.push(instruction);
+
+        result.resize(result.len().div_ceil(3) * 3, 0);
+        ip_map.push((i, next_ip(result.len(), i)?));
+
+        for instruction in expansion {
+            instruction_ips.insert(next_ip(result.len(), i)?);
+            let bytes = instruction.to_bytes(ctx.instruction_indices, ctx.opcodes);
+            // Operands can collide with opcodes, so make sure the decoder reads back what we meant
+            let decoded = Instruction::from_bytes(&bytes, ctx.instruction_indices, ctx.opcodes);
+            if decoded != Some(instruction) {
+                warnings.push(AsmWarning::AmbiguousEncoding {
                     line: i,
+                    intended: instruction,
+                    decoded,
+                });
+            }
+            if let (Instruction::Imm { val, .. }, Some(mask)) = (instruction, ctx.immediate_mask) {
+                if val & !mask != 0 {
+                    warnings.push(AsmWarning::ImmediateMasked {
+                        line: i,
+                        value: val,
+                        mask,
+                    });
+                }
+            }
+            result.extend_from_slice(&bytes);
+            instructions += 1;
+        }
+        Ok(())
+    };
+    for (i, line) in lines {
+        if let Err(err) = assemble_line(*i, line) {
+            errors.push(err);
+        }
+    }
+    // A target past the end isn't checked, only one that lands on bytes some directive put there
+    for (line, target) in jumps {
+        if labels.is_some()
+            && (target as usize) * 3 < result.len()
+            && !instruction_ips.contains(&target)
+        {
+            errors.push(InvalidInstruction::InvalidJumpTarget { target, line });
+        }
+    }
+    if let Some((_, line)) = conditions.pop() {
+        errors.push(InvalidInstruction::UnmatchedConditional { line });
+    }
+    let assembly = Assembly {
+        program: result,
+        entry,
+        instructions,
+        warnings,
+        labels: defined_labels,
+        ip_map,
+    };
+    (assembly, errors)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AsmWarning {
+    JumpTargetNotInstruction {
+        ip: u8,
+        target: u8,
+    },
+    AmbiguousEncoding {
+        line: usize,
+        intended: Instruction,
+        decoded: Option<Instruction>,
+    },
+    ImmediateMasked {
+        line: usize,
+        value: u8,
+        mask: u8,
+    },
+    DirectIpWrite {
+        ip: u8,
+        instruction: Instruction,
+    },
+}
+
+impl fmt::Display for AsmWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmWarning::JumpTargetNotInstruction { ip, target } => {
+                write!(
+                    f,
+                    "JMP at ip {} targets ip {}, which is not the start of an instruction",
+                    ip, target
+                )
+            }
+            AsmWarning::AmbiguousEncoding {
+                line,
+                intended,
+                decoded: Some(decoded),
+            } => {
+                write!(
+                    f,
+                    "Instruction at line {} decodes as {} instead of {}",
+                    line, decoded, intended
+                )
+            }
+            AsmWarning::AmbiguousEncoding {
+                line,
+                intended,
+                decoded: None,
+            } => {
+                write!(
+                    f,
+                    "Instruction at line {} doesn't decode back to {}",
+                    line, intended
+                )
+            }
+            AsmWarning::DirectIpWrite { ip, instruction } => {
+                write!(
+                    f,
+                    "{} at ip {} writes the ip register directly, use JMP instead",
+                    instruction, ip
+                )
+            }
+            AsmWarning::ImmediateMasked { line, value, mask } => {
+                write!(
+                    f,
+                    "IMM at line {} loads {:#04x}, but the VM only uses the bits in {:#04x}",
+                    line, value, mask
+                )
+            }
+        }
+    }
+}
+
+//Jump targets are whatever the dst register holds, so only the ones loaded by an IMM can be checked.
+//A target is fine only if some line was assembled at it, which also catches jumps into .space/.string data
+pub fn lint_jump_targets(program: &[u8], ip_map: &IpMap, ctx: VMConsts) -> Vec<AsmWarning> {
+    constant_jump_targets(program, &ctx)
+        .into_iter()
+        .filter(|(_, target)| !ip_map.iter().any(|(_, ip)| ip == target))
+        .map(|(ip, target)| AsmWarning::JumpTargetNotInstruction { ip, target })
+        .collect()
+}
+
+//Writing i is a jump that skips the flags check, which is rarely what was meant outside a JMP
+pub fn lint_ip_writes(program: &[u8], ctx: VMConsts) -> Vec<AsmWarning> {
+    program
+        .chunks(3)
+        .enumerate()
+        .filter_map(|(ip, instruction_bytes)| {
+            let instruction =
+                Instruction::from_bytes(instruction_bytes, ctx.instruction_indices, ctx.opcodes)?;
+            let written = match instruction {
+                Instruction::Imm { dst, .. }
+                | Instruction::Add { dst, .. }
+                | Instruction::Ldm { dst, .. }
+                | Instruction::Sys { dst, .. }
+                | Instruction::Stk { pop: dst, .. } => dst,
+                _ => return None,
+            };
+            (written == ctx.registers.i).then_some(AsmWarning::DirectIpWrite {
+                ip: ip as u8,
+                instruction,
+            })
+        })
+        .collect()
+}
+
+//Assembles src and disassembles the result, one line per 3 bytes, to show how the source was actually encoded.
+//Bytes that don't decode (e.g. .space) are shown as a comment
+pub fn roundtrip_preview(src: &str, ctx: VMConsts) -> Result<String, InvalidInstruction> {
+    let program = assemble(src.to_string(), ctx)?;
+    let mut preview = String::new();
+    for instruction_bytes in program.chunks(3) {
+        match Instruction::from_bytes(instruction_bytes, ctx.instruction_indices, ctx.opcodes) {
+            Some(instruction) => preview.push_str(&instruction.to_assembly(&ctx)),
+            None => {
+                let bytes: Vec<String> = instruction_bytes
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                preview.push_str(&format!("; doesn't decode: {}", bytes.join(" ")));
+            }
+        }
+        preview.push('\n');
+    }
+    Ok(preview)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{
+        emulator::{Emulator, RunOutcome},
+        testing::{assert_assembles_to, consts},
+    };
+
+    #[test]
+    fn lint_flags_jump_past_the_last_instruction() {
+        let (program, ip_map) =
+            assemble_with_ip_map("IMM d 9\nJMP 0 d".to_string(), consts()).unwrap();
+        assert_eq!(
+            lint_jump_targets(&program, &ip_map, consts()),
+            vec![AsmWarning::JumpTargetNotInstruction { ip: 1, target: 9 }]
+        );
+    }
+
+    #[test]
+    fn lint_flags_jump_into_data() {
+        let src = "IMM d 2\nJMP 0 d\n.space 3\nIMM a 1";
+        let (program, ip_map) = assemble_with_ip_map(src.to_string(), consts()).unwrap();
+        assert_eq!(
+            lint_jump_targets(&program, &ip_map, consts()),
+            vec![AsmWarning::JumpTargetNotInstruction { ip: 1, target: 2 }]
+        );
+    }
+
+    #[test]
+    fn lint_accepts_jump_to_an_instruction() {
+        let src = "IMM d 3\nJMP 0 d\n.space 3\nIMM a 1";
+        let (program, ip_map) = assemble_with_ip_map(src.to_string(), consts()).unwrap();
+        assert!(lint_jump_targets(&program, &ip_map, consts()).is_empty());
+    }
+
+    #[test]
+    fn special_register_as_scratch_is_rejected() {
+        let mut ctx = consts();
+        ctx.scratch = Some(ctx.registers.s);
+        assert!(matches!(
+            assemble("JMP 0 end\nend: IMM a 1".to_string(), ctx),
+            Err(InvalidInstruction::InvalidConfig {
+                error: ConfigError::InvalidScratchRegister { .. }
+            })
+        ));
+    }
+
+    #[test]
+    fn entry_reports_the_ip_of_its_label() {
+        let src = "IMM a 1\nIMM b 2\n.entry start\nstart: ADD a b\nIMM c 3";
+        let (program, entry) = assemble_with_entry(src.to_string(), consts()).unwrap();
+        assert_eq!(entry, 2);
+        assert_eq!(program.len(), 12);
+    }
+
+    #[test]
+    fn entry_defaults_to_zero() {
+        let (_, entry) = assemble_with_entry("IMM a 1".to_string(), consts()).unwrap();
+        assert_eq!(entry, 0);
+    }
+
+    #[test]
+    fn stats_count_instructions_and_bytes() {
+        let src = ".equ K 3\nstart: IMM a K\nIMM b 1\n\nADD a b ; comment\nSTK NONE a";
+        let (program, stats) = assemble_with_stats(src.to_string(), consts()).unwrap();
+        assert_eq!(
+            stats,
+            AsmStats {
+                instructions: 4,
+                bytes: 12
+            }
+        );
+        assert_eq!(program.len(), stats.bytes);
+        assert_eq!(stats.to_string(), "assembled 4 instructions (12 bytes)");
+    }
+
+    #[test]
+    fn if_follows_its_constant() {
+        let src = |debug: u8| {
+            format!(
+                ".equ DEBUG {}\nIMM a 1\n.if DEBUG\nIMM b 2\n.endif\nIMM c 3",
+                debug
+            )
+        };
+        let on = assemble(src(1), consts()).unwrap();
+        let off = assemble(src(0), consts()).unwrap();
+        assert_eq!(
+            on,
+            assemble("IMM a 1\nIMM b 2\nIMM c 3".to_string(), consts()).unwrap()
+        );
+        assert_eq!(
+            off,
+            assemble("IMM a 1\nIMM c 3".to_string(), consts()).unwrap()
+        );
+    }
+
+    #[test]
+    fn nested_if_needs_every_condition() {
+        let src = ".equ A 1\n.equ B 0\n.if A\nIMM a 1\n.if B\nIMM b 2\n.endif\n.endif";
+        assert_eq!(
+            assemble(src.to_string(), consts()).unwrap(),
+            assemble("IMM a 1".to_string(), consts()).unwrap()
+        );
+    }
+
+    #[test]
+    fn unclosed_if_is_rejected() {
+        let src = ".equ A 1\n.if A\nIMM a 1";
+        assert!(matches!(
+            assemble(src.to_string(), consts()),
+            Err(InvalidInstruction::UnmatchedConditional { line: 1 })
+        ));
+    }
+
+    #[test]
+    fn warns_when_an_instruction_decodes_as_another() {
+        let mut ctx = consts();
+        ctx.opcodes.imm = ctx.opcodes.sys;
+        let a = ctx.registers.a;
+        let (_, warnings) = assemble_with_warnings("IMM a 5".to_string(), ctx).unwrap();
+        assert_eq!(
+            warnings,
+            vec![AsmWarning::AmbiguousEncoding {
+                line: 0,
+                intended: Instruction::Imm { dst: a, val: 5 },
+                decoded: Some(Instruction::Sys { num: a, dst: 5 }),
+            }]
+        );
+        let (_, warnings) = assemble_with_warnings("IMM a 5".to_string(), consts()).unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn labels_and_comments_share_a_line() {
+        let plain = assemble("IMM a 1\nIMM b 2\nADD a b".to_string(), consts()).unwrap();
+        let src = "IMM a 1 ; instruction and comment\nmid: ; label and comment\nIMM b 2\n\
+                   end: ADD a b ; label, instruction and comment";
+        assert_eq!(assemble(src.to_string(), consts()).unwrap(), plain);
+        for (label, ip) in [("mid", 1), ("end", 2)] {
+            let with_entry = format!("{}\n.entry {}", src, label);
+            let (program, entry) = assemble_with_entry(with_entry, consts()).unwrap();
+            assert_eq!((program, entry), (plain.clone(), ip));
+        }
+    }
+
+    #[test]
+    fn ip_map_accounts_for_space() {
+        let src = "IMM a 1\n.space 4\nIMM b 2";
+        let (program, ip_map) = assemble_with_ip_map(src.to_string(), consts()).unwrap();
+        assert_eq!(ip_map, vec![(0, 0), (2, 3)]);
+        assert_eq!(program.len(), 12);
+        assert_eq!(program[3..9], [0; 6]);
+    }
+
+    #[test]
+    fn space_takes_a_label_defined_above() {
+        let src = "IMM a 1\nsecond: IMM b 2\n.space second\nIMM c 3";
+        let (_, ip_map) = assemble_with_ip_map(src.to_string(), consts()).unwrap();
+        assert_eq!(ip_map, vec![(0, 0), (1, 1), (3, 3)]);
+    }
+
+    #[test]
+    fn space_rejects_a_label_defined_below() {
+        let src = ".space end\nend: IMM a 1";
+        assert!(matches!(
+            assemble(src.to_string(), consts()),
+            Err(InvalidInstruction::ForwardReference { label, line: 0 }) if label == "end"
+        ));
+    }
+
+    #[test]
+    fn program_past_ip_255_is_rejected() {
+        let full = "IMM a 1\n".repeat(256);
+        assert_eq!(assemble(full.clone(), consts()).unwrap().len(), 256 * 3);
+        assert!(matches!(
+            assemble(full.clone() + "IMM a 1", consts()),
+            Err(InvalidInstruction::ProgramTooLarge { line: 256 })
+        ));
+        assert!(matches!(
+            assemble(full + "end:", consts()),
+            Err(InvalidInstruction::ProgramTooLarge { line: 256 })
+        ));
+    }
+
+    #[test]
+    fn assembled_file_loads_and_runs() {
+        let path = std::env::temp_dir().join(format!("yan85-asm-{}.bin", std::process::id()));
+        assemble_to_file(
+            "IMM a 3\nIMM b 4\nADD a b\nSYS 0x20 d".to_string(),
+            consts(),
+            &path,
+        )
+        .unwrap();
+        let mut mem = read_bytecode(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        mem.resize(0x407, 0);
+        let mut e = Emulator::new(mem, consts());
+        assert_eq!(e.run(10).unwrap(), RunOutcome::Halted);
+        assert_eq!(e.exit_code(), Some(7));
+    }
+
+    #[test]
+    fn parse_line_instruction() {
+        let ctx = consts();
+        assert_eq!(
+            parse_line("loop: IMM a K ; load", 0, &ctx).unwrap(),
+            ParsedLine::Instruction {
+                label: Some("loop".to_string()),
+                mnemonic: "IMM".to_string(),
+                operands: vec![
+                    Operand::Register(ctx.registers.a),
+                    Operand::Value("K".to_string())
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_line_directive() {
+        assert_eq!(
+            parse_line(".EQU K 5", 0, &consts()).unwrap(),
+            ParsedLine::Directive {
+                label: None,
+                name: ".equ".to_string(),
+                args: vec!["K".to_string(), "5".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_line_label_and_blank() {
+        assert_eq!(
+            parse_line("end: ; nothing else", 0, &consts()).unwrap(),
+            ParsedLine::Label("end".to_string())
+        );
+        assert_eq!(
+            parse_line("   ; only a comment", 0, &consts()).unwrap(),
+            ParsedLine::Blank
+        );
+        assert_eq!(parse_line("", 0, &consts()).unwrap(), ParsedLine::Blank);
+    }
+
+    #[test]
+    fn parse_line_rejects_a_bad_instruction() {
+        assert!(matches!(
+            parse_line("IMM q 1", 3, &consts()),
+            Err(InvalidInstruction::InvalidRegister { line: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn raw_register_bytes_are_taken_as_is() {
+        let ctx = consts();
+        let expected = Instruction::Imm { dst: 0x20, val: 5 }
+            .to_bytes(ctx.instruction_indices, ctx.opcodes)
+            .to_vec();
+        assert_eq!(assemble("IMM r0x20 5".to_string(), ctx).unwrap(), expected);
+        assert_eq!(assemble("IMM r32 5".to_string(), ctx).unwrap(), expected);
+        assert!(matches!(
+            assemble_strict("IMM r0x20 5".to_string(), ctx),
+            Err(InvalidInstruction::InvalidRegister { .. })
+        ));
+    }
+
+    #[test]
+    fn string_argument_keeps_its_spaces() {
+        let program = assemble(".string \"a b\"".to_string(), consts()).unwrap();
+        assert_eq!(program, b"a b\0");
+        assert!(matches!(
+            assemble("IMM a".to_string(), consts()),
+            Err(InvalidInstruction::InvalidNumberOfParts { lines: 2, line: 0 })
+        ));
+    }
+
+    #[test]
+    fn macro_expands_at_every_use() {
+        let src = ".macro load\nIMM a \\1\nIMM b \\2\n.endm\nload 1 2\nADD a b\nload 3 4";
+        let expanded = "IMM a 1\nIMM b 2\nADD a b\nIMM a 3\nIMM b 4";
+        assert_eq!(
+            assemble(src.to_string(), consts()).unwrap(),
+            assemble(expanded.to_string(), consts()).unwrap()
+        );
+    }
+
+    #[test]
+    fn recursive_macro_is_rejected() {
+        let src = ".macro loop\nloop\n.endm\nloop";
+        assert!(matches!(
+            assemble(src.to_string(), consts()),
+            Err(InvalidInstruction::RecursiveMacro { .. })
+        ));
+    }
+
+    #[test]
+    fn errors_in_macros_point_at_the_use() {
+        let src = ".macro bad\nIMM q 1\n.endm\nIMM a 1\nbad";
+        assert!(matches!(
+            assemble(src.to_string(), consts()),
+            Err(InvalidInstruction::InvalidRegister { line: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn lowercase_source_assembles_the_same() {
+        let upper = "Start: IMM a 1\n.EQU K 3\nSTK NONE a\nIMM b Start\n.MACRO M\nADD a b\n.ENDM\nM\nJMP ALWAYS Start";
+        let lower = "Start: imm a 1\n.equ K 3\nstk none a\nimm b Start\n.macro M\nadd a b\n.endm\nM\njmp always Start";
+        assert_eq!(
+            assemble(lower.to_string(), consts()).unwrap(),
+            assemble(upper.to_string(), consts()).unwrap()
+        );
+    }
+
+    #[test]
+    fn label_names_keep_their_case() {
+        let src = "Start: IMM a 1\nIMM b start";
+        assert!(matches!(
+            assemble(src.to_string(), consts()),
+            Err(InvalidInstruction::UndefinedLabel { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn immediate_outside_the_mask_warns() {
+        let mut ctx = consts();
+        ctx.immediate_mask = Some(0x0F);
+        let (_, warnings) = assemble_with_warnings("IMM a 5\nIMM a 0xFF".to_string(), ctx).unwrap();
+        assert_eq!(
+            warnings,
+            vec![AsmWarning::ImmediateMasked {
+                line: 1,
+                value: 0xFF,
+                mask: 0x0F
+            }]
+        );
+    }
+
+    #[test]
+    fn assert_assembles_to_accepts_matching_bytes() {
+        assert_assembles_to("IMM a 5\nADD a b", consts(), "01 10 05 10 10 08");
+    }
+
+    #[test]
+    #[should_panic(expected = "byte 2: expected 06, got 05")]
+    fn assert_assembles_to_reports_the_differing_byte() {
+        assert_assembles_to("IMM a 5", consts(), "01 10 06");
+    }
+
+    #[test]
+    fn direct_ip_write_is_linted_but_jmp_is_not() {
+        let program = assemble("IMM i 5".to_string(), consts()).unwrap();
+        assert!(matches!(
+            lint_ip_writes(&program, consts()).as_slice(),
+            [AsmWarning::DirectIpWrite { ip: 0, .. }]
+        ));
+        let program = assemble("JMP ALWAYS end\nend: IMM a 1".to_string(), consts()).unwrap();
+        assert!(lint_ip_writes(&program, consts()).is_empty());
+    }
+
+    #[test]
+    fn roundtrip_preview_shows_the_decoded_instruction() {
+        // Written back in assembler syntax, so the preview can itself be assembled
+        let preview = roundtrip_preview("IMM a 5\nadd A b", consts()).unwrap();
+        assert_eq!(preview, "IMM a 5\nADD a b\n");
+    }
+
+    #[test]
+    fn jmp_loop_round_trips() {
+        let ctx = consts();
+        let src = "IMM a 0\nIMM b 1\nIMM c 3\nIMM d 3\nADD a b\nCMP a c\nJMP 0x2 d";
+        let program = assemble(src.to_string(), ctx).unwrap();
+        assert_eq!(program.len(), 7 * 3);
+        let decoded: Vec<Instruction> = program
+            .chunks(3)
+            .map(|bytes| {
+                Instruction::from_bytes(bytes, ctx.instruction_indices, ctx.opcodes).unwrap()
+            })
+            .collect();
+        assert_eq!(
+            decoded[6],
+            Instruction::Jmp {
+                flags: ctx.cmp_flags.smaller,
+                dst: ctx.registers.d
+            }
+        );
+        let round_tripped: Vec<String> = decoded.iter().map(|i| i.to_assembly(&ctx)).collect();
+        assert_eq!(round_tripped.join("\n"), src.replace("0x2", "2"));
+    }
+
+    #[test]
+    fn crlf_source_assembles_like_lf() {
+        let lf = "IMM a 1\nIMM b 0x2\nADD a b\n";
+        let crlf = lf.replace('\n', "\r\n");
+        assert_eq!(
+            assemble(crlf.clone(), consts()).unwrap(),
+            assemble(lf.to_string(), consts()).unwrap()
+        );
+        // assemble_lines gets lines split on \n only, so every token before the break carries the \r
+        assert_eq!(
+            assemble_lines(crlf.split('\n'), consts()).unwrap(),
+            assemble(lf.to_string(), consts()).unwrap()
+        );
+    }
+
+    #[test]
+    fn operands_land_at_the_configured_indices() {
+        let mut ctx = consts();
+        ctx.instruction_indices.opcode = 2;
+        ctx.instruction_indices.left_param = 0;
+        ctx.instruction_indices.right_param = 1;
+        let program = assemble("IMM a 5\nSTK NONE b".to_string(), ctx).unwrap();
+        assert_eq!(program[..3], [ctx.registers.a, 5, ctx.opcodes.imm]);
+        let decoded: Vec<_> = program
+            .chunks(3)
+            .map(|bytes| Instruction::from_bytes(bytes, ctx.instruction_indices, ctx.opcodes))
+            .collect();
+        assert_eq!(
+            decoded,
+            vec![
+                Some(Instruction::Imm {
+                    dst: ctx.registers.a,
+                    val: 5
+                }),
+                Some(Instruction::Stk {
+                    pop: ctx.registers.none,
+                    push: ctx.registers.b
                 })
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_are_stripped_and_comment_lines_skipped() {
+        let plain = assemble("IMM a 1\nIMM b 2".to_string(), consts()).unwrap();
+        let src = "; header\nIMM a 1 ; inline\n   # hash comment\nIMM b 2 # inline hash\n;";
+        assert_eq!(assemble(src.to_string(), consts()).unwrap(), plain);
+        let (_, stats) = assemble_with_stats(src.to_string(), consts()).unwrap();
+        assert_eq!(stats.instructions, 2);
+    }
+
+    #[test]
+    fn blank_lines_between_instructions_are_ignored() {
+        let dense = "IMM a 1\nIMM b 2\nADD a b";
+        let spaced = "\nIMM a 1\n\n   \nIMM b 2\n\t\nADD a b\n\n";
+        assert_eq!(
+            assemble(spaced.to_string(), consts()).unwrap(),
+            assemble(dense.to_string(), consts()).unwrap()
+        );
+    }
+
+    #[test]
+    fn numbers_in_every_base() {
+        assert_eq!(parse_num("42", 0).unwrap(), 42);
+        assert_eq!(parse_num("0x2a", 0).unwrap(), 42);
+        assert_eq!(parse_num("0b101010", 0).unwrap(), 42);
+        assert_eq!(parse_num("0xFF", 0).unwrap(), 255);
+        for bad in ["0x1FF", "256", "0b102", "0x", "abc"] {
+            assert!(
+                matches!(
+                    parse_num(bad, 7),
+                    Err(InvalidInstruction::InvalidNumber { line: 7, .. })
+                ),
+                "{} parsed",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn assemble_lines_matches_assemble() {
+        let src = ".equ K 4\nstart: IMM a K\n\nADD a b ; comment\nJMP ALWAYS start\n";
+        assert_eq!(
+            assemble_lines(src.lines(), consts()).unwrap(),
+            assemble(src.to_string(), consts()).unwrap()
+        );
+        let reader = io::BufReader::new(src.as_bytes());
+        let lines: Vec<String> = io::BufRead::lines(reader).map(Result::unwrap).collect();
+        assert_eq!(
+            assemble_lines(lines.iter().map(String::as_str), consts()).unwrap(),
+            assemble(src.to_string(), consts()).unwrap()
+        );
+    }
+
+    #[test]
+    fn labels_resolve_forward_and_backward() {
+        let ctx = consts();
+        let src = "JMP 0 end\nloop:\nIMM a 1\nJMP 0 loop\nend: IMM b 2";
+        let program = assemble(src.to_string(), ctx).unwrap();
+        let decoded = |ip: usize| {
+            Instruction::from_bytes(
+                &program[ip * 3..ip * 3 + 3],
+                ctx.instruction_indices,
+                ctx.opcodes,
+            )
+        };
+        // Each JMP to a label is IMM scratch target; JMP 0 scratch
+        assert_eq!(
+            decoded(0),
+            Some(Instruction::Imm {
+                dst: ctx.scratch_register(),
+                val: 5
+            })
+        );
+        assert_eq!(
+            decoded(3),
+            Some(Instruction::Imm {
+                dst: ctx.scratch_register(),
+                val: 2
+            })
+        );
+    }
+
+    #[test]
+    fn undefined_label_is_reported() {
+        assert!(matches!(
+            assemble("IMM a 1\nJMP 0 nowhere".to_string(), consts()),
+            Err(InvalidInstruction::UndefinedLabel { ref label, line: 1 }) if label == "nowhere"
+        ));
+    }
+
+    #[test]
+    fn jump_to_label_goes_through_the_configured_scratch() {
+        let mut ctx = consts();
+        ctx.scratch = Some(ctx.registers.c);
+        let program = assemble("IMM d 7\nJMP 0 end\nend: IMM a 1".to_string(), ctx).unwrap();
+        let mut expected = Instruction::Imm {
+            dst: ctx.registers.c,
+            val: 3,
+        }
+        .to_bytes(ctx.instruction_indices, ctx.opcodes)
+        .to_vec();
+        expected.extend(
+            Instruction::Jmp {
+                flags: 0,
+                dst: ctx.registers.c,
             }
+            .to_bytes(ctx.instruction_indices, ctx.opcodes),
+        );
+        assert_eq!(program[3..9], expected);
+    }
+
+    #[test]
+    fn assemble_all_reports_every_broken_line() {
+        let src = "IMM a 1\nIMM q 1\nADD a b\nFOO a b\nIMM a 0x1FF\nSTK NONE a";
+        let errors = assemble_all(src.to_string(), consts()).unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(
+            errors[0],
+            InvalidInstruction::InvalidRegister { line: 1, .. }
+        ));
+        assert!(matches!(
+            errors[1],
+            InvalidInstruction::InvalidOperation { line: 3, .. }
+        ));
+        assert!(matches!(
+            errors[2],
+            InvalidInstruction::InvalidNumber { line: 4, .. }
+        ));
+        // assemble stops at the first one
+        assert!(matches!(
+            assemble(src.to_string(), consts()),
+            Err(InvalidInstruction::InvalidRegister { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn jump_into_space_is_rejected() {
+        let src = "JMP ALWAYS data\n.space 2\ndata: .space 4\nIMM a 1";
+        assert!(matches!(
+            assemble(src.to_string(), consts()),
+            Err(InvalidInstruction::InvalidJumpTarget { line: 0, .. })
+        ));
+        let ok = "JMP ALWAYS code\n.space 2\ncode: IMM a 1";
+        assert!(assemble(ok.to_string(), consts()).is_ok());
+    }
+
+    fn jump_flags(flags: &str) -> Result<u8, InvalidInstruction> {
+        let program = assemble(format!("JMP {} d", flags), consts())?;
+        Ok(program[1])
+    }
+
+    #[test]
+    fn jump_flag_mnemonics() {
+        let flags = consts().cmp_flags;
+        assert_eq!(jump_flags("Z").unwrap(), flags.zero);
+        assert_eq!(jump_flags("L").unwrap(), flags.smaller);
+        assert_eq!(jump_flags("G").unwrap(), flags.bigger);
+        assert_eq!(jump_flags("E").unwrap(), flags.equals);
+        assert_eq!(jump_flags("N").unwrap(), flags.not_equals);
+        assert_eq!(jump_flags("L|E").unwrap(), flags.smaller | flags.equals);
+        assert_eq!(jump_flags("ALWAYS").unwrap(), 0);
+        assert_eq!(jump_flags("0").unwrap(), 0);
+        assert!(matches!(
+            jump_flags("Q"),
+            Err(InvalidInstruction::InvalidJumpFlags { ref flags, line: 0 }) if flags == "Q"
+        ));
+    }
+
+    #[test]
+    fn register_names_ignore_case() {
+        assert_eq!(
+            assemble("ADD A B".to_string(), consts()).unwrap(),
+            assemble("add a b".to_string(), consts()).unwrap()
+        );
+        assert_eq!(
+            assemble("STK None A".to_string(), consts()).unwrap(),
+            assemble("STK NONE a".to_string(), consts()).unwrap()
+        );
+    }
+
+    #[test]
+    fn binary_literals_and_digit_separators() {
+        assert_eq!(parse_num("0b1010_1010", 0).unwrap(), 0xAA);
+        assert_eq!(parse_num("1_0", 0).unwrap(), 10);
+        for bad in ["1__0", "_1", "1_", "0b2"] {
+            assert!(
+                matches!(
+                    parse_num(bad, 3),
+                    Err(InvalidInstruction::InvalidNumber { line: 3, .. })
+                ),
+                "{bad}"
+            );
         }
     }
-    return Ok(result);
 }
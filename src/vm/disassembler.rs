@@ -0,0 +1,249 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    error, fmt, fs, io,
+    path::Path,
+};
+
+use super::{
+    analysis::{constant_jump_targets, jump_target_sources},
+    arch::{Instruction, VMConsts},
+    emulator::EmulationError,
+};
+
+//One line of assembly per instruction. Errors say at which byte offset decoding went wrong
+pub fn disassemble(program: &[u8], ctx: VMConsts) -> Result<String, EmulationError> {
+    let mut listing = String::new();
+    for (i, instruction_bytes) in program.chunks(3).enumerate() {
+        let offset = i * 3;
+        let bytes: [u8; 3] =
+            instruction_bytes
+                .try_into()
+                .map_err(|_| EmulationError::TruncatedInstruction {
+                    offset,
+                    len: instruction_bytes.len(),
+                })?;
+        let instruction = Instruction::from_bytes(&bytes, ctx.instruction_indices, ctx.opcodes)
+            .ok_or(EmulationError::UndecodableBytes { offset, bytes })?;
+        listing.push_str(&instruction.to_assembly(&ctx));
+        listing.push('\n');
+    }
+    Ok(listing)
+}
+
+//Same as disassemble, but jump targets loaded by an IMM get a label (L0, L1, ... in ip order) and the IMM
+//refers to it by name, so the listing can be edited and assembled back
+pub fn disassemble_with_labels(program: &[u8], ctx: VMConsts) -> Result<String, EmulationError> {
+    let listing = disassemble(program, ctx)?;
+    let count = program.len() / 3;
+
+    let sources = jump_target_sources(program, &ctx);
+    let targets: BTreeSet<u8> = sources
+        .iter()
+        .map(|(_, _, target)| *target)
+        .filter(|target| (*target as usize) < count)
+        .collect();
+    let labels: HashMap<u8, String> = targets
+        .iter()
+        .enumerate()
+        .map(|(n, target)| (*target, format!("L{}", n)))
+        .collect();
+    let loads: HashMap<u8, &String> = sources
+        .iter()
+        .filter_map(|(source, _, target)| Some((*source, labels.get(target)?)))
+        .collect();
+
+    let mut result = String::new();
+    for (ip, line) in listing.lines().enumerate() {
+        let ip = ip as u8;
+        if let Some(label) = labels.get(&ip) {
+            result.push_str(&format!("{}:\n", label));
+        }
+        match (loads.get(&ip), line.rsplit_once(' ')) {
+            // IMM's value is its last operand
+            (Some(label), Some((imm, _))) => result.push_str(&format!("{} {}", imm, label)),
+            _ => result.push_str(line),
+        }
+        result.push('\n');
+    }
+    Ok(result)
+}
+
+#[derive(Debug)]
+pub enum DisassembleFileError {
+    Disassembly(EmulationError),
+    Io(io::Error),
+}
+
+impl error::Error for DisassembleFileError {}
+
+impl fmt::Display for DisassembleFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisassembleFileError::Disassembly(err) => write!(f, "{}", err),
+            DisassembleFileError::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl From<EmulationError> for DisassembleFileError {
+    fn from(err: EmulationError) -> Self {
+        DisassembleFileError::Disassembly(err)
+    }
+}
+
+impl From<io::Error> for DisassembleFileError {
+    fn from(err: io::Error) -> Self {
+        DisassembleFileError::Io(err)
+    }
+}
+
+//Writes assembly source for the program, with labels, that assembles back to the same bytes
+pub fn disassemble_to_file(
+    program: &[u8],
+    ctx: VMConsts,
+    path: impl AsRef<Path>,
+) -> Result<(), DisassembleFileError> {
+    let source = disassemble_with_labels(program, ctx)?;
+    fs::write(path, source)?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: u8,           // ip of the first instruction
+    pub last: u8,            // ip of the last instruction
+    pub successors: Vec<u8>, // Start of every block control can go to next. Jumps with a target unknown statically add none
+}
+
+impl BasicBlock {
+    pub fn label(&self) -> String {
+        format!("block_{}", self.start)
+    }
+}
+
+//Splits the program into basic blocks, up to the first instruction that doesn't decode.
+//Blocks start at ip 0, at constant jump targets and right after jumps
+pub fn disassemble_blocks(program: &[u8], ctx: &VMConsts) -> Vec<BasicBlock> {
+    let instructions: Vec<Instruction> = program
+        .chunks(3)
+        .map_while(|instruction_bytes| {
+            Instruction::from_bytes(instruction_bytes, ctx.instruction_indices, ctx.opcodes)
+        })
+        .collect();
+    let count = instructions.len();
+    if count == 0 {
+        return vec![];
+    }
+
+    let targets: HashMap<u8, u8> = constant_jump_targets(program, ctx)
+        .into_iter()
+        .filter(|(ip, _)| (*ip as usize) < count)
+        .collect();
+
+    let mut leaders: BTreeSet<usize> = BTreeSet::from([0]);
+    for (ip, instruction) in instructions.iter().enumerate() {
+        match instruction {
+            Instruction::Jmp { .. } => {
+                leaders.insert(ip + 1);
+                if let Some(target) = targets.get(&(ip as u8)) {
+                    leaders.insert(*target as usize);
+                }
+            }
+            Instruction::Halt => {
+                leaders.insert(ip + 1);
+            }
+            _ => {}
+        }
+    }
+    leaders.retain(|leader| *leader < count);
+
+    let starts: Vec<usize> = leaders.into_iter().collect();
+    starts
+        .iter()
+        .enumerate()
+        .map(|(n, start)| {
+            let end = starts.get(n + 1).copied().unwrap_or(count);
+            let last = end - 1;
+            let mut successors = vec![];
+            let falls_through = match instructions[last] {
+                Instruction::Jmp { flags, .. } => {
+                    if let Some(target) = targets.get(&(last as u8)) {
+                        if (*target as usize) < count {
+                            successors.push(*target);
+                        }
+                    }
+                    flags != 0
+                }
+                Instruction::Halt => false,
+                _ => true,
+            };
+            if falls_through && end < count {
+                successors.insert(0, end as u8);
+            }
+            BasicBlock {
+                start: *start as u8,
+                last: last as u8,
+                successors,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{assembler::assemble, testing::consts};
+
+    #[test]
+    fn conditional_branch_splits_three_blocks() {
+        let src = "IMM a 1\nCMP a b\nJMP E skip\nIMM a 2\nskip: IMM c 3";
+        let program = assemble(src.to_string(), consts()).unwrap();
+        let block = |start, last, successors: &[u8]| BasicBlock {
+            start,
+            last,
+            successors: successors.to_vec(),
+        };
+        assert_eq!(
+            disassemble_blocks(&program, &consts()),
+            vec![block(0, 3, &[4, 5]), block(4, 4, &[5]), block(5, 5, &[])]
+        );
+    }
+
+    #[test]
+    fn labeled_source_assembles_back_to_the_same_bytes() {
+        let src = "IMM a 0\nIMM b 1\nIMM c 5\nloop: ADD a b\nCMP a c\nJMP L loop\nJMP ALWAYS done\nIMM a 9\ndone: SYS 0x20 d";
+        let program = assemble(src.to_string(), consts()).unwrap();
+        let path = std::env::temp_dir().join(format!("yan85-disasm-{}.s", std::process::id()));
+        disassemble_to_file(&program, consts(), &path).unwrap();
+        let source = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(source.contains("L0:") && source.contains("L1:"));
+        assert_eq!(assemble(source, consts()).unwrap(), program);
+    }
+
+    #[test]
+    fn disassembly_assembles_back() {
+        let src = "IMM a 5\nADD a b\nSTK NONE a\nSTM b a\nLDM c b\nCMP a c\nJMP 16 d\nSYS 4 d\n";
+        let program = assemble(src.to_string(), consts()).unwrap();
+        let listing = disassemble(&program, consts()).unwrap();
+        assert_eq!(listing, src);
+        assert_eq!(assemble(listing, consts()).unwrap(), program);
+    }
+
+    #[test]
+    fn disassembly_errors_say_where() {
+        let mut program = assemble("IMM a 5\nIMM b 6".to_string(), consts()).unwrap();
+        program[3] = 0x03;
+        assert!(matches!(
+            disassemble(&program, consts()),
+            Err(EmulationError::UndecodableBytes {
+                offset: 3,
+                bytes: [0x03, _, 6]
+            })
+        ));
+        assert!(matches!(
+            disassemble(&[0x01, 0x10, 5, 0x01], consts()),
+            Err(EmulationError::TruncatedInstruction { offset: 3, len: 1 })
+        ));
+    }
+}
@@ -1,8 +1,10 @@
-use libc::c_int;
-
 use crate::vm::arch::*;
-use std::ffi::CString;
+use crate::vm::disassembler;
+use crate::vm::hexdump::hex_dump;
+use crate::vm::io::{LibcIo, MockIo, SyscallIo};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::ops::Range;
 
 use std::error;
 
@@ -10,8 +12,22 @@ use std::error;
 pub enum EmulationError {
     InvalidRegister { register: reg },
     InvalidInstruction { instruction: u8 },
+    InvalidInstructionAt { ip: u8, instruction: u8 },
+    UndecodableBytes { offset: usize, bytes: [u8; 3] },
+    TruncatedInstruction { offset: usize, len: usize },
     InvalidMemoryAddress { address: u16 },
     InvalidSyscall { syscall: u8 },
+    SyscallNotAllowed { syscall: u8 },
+    SyscallBudgetExceeded,
+    StackOverflow,
+    StackUnderflow,
+    StepLimitExceeded,
+    Halted,
+    RegisterRegionUnbacked { size: usize, required: usize },
+    InvalidConfig { error: ConfigError },
+    CodeTooLarge { size: usize, max: usize },
+    ExecutingData { address: u16 },
+    ProtectionFault { address: u16, access: Access },
     OtherError,
 }
 
@@ -25,12 +41,62 @@ impl fmt::Display for EmulationError {
             EmulationError::InvalidInstruction { instruction } => {
                 write!(f, "Invalid instruction: {}", instruction)
             }
+            EmulationError::InvalidInstructionAt { ip, instruction } => {
+                write!(f, "Invalid instruction at ip {}: {}", ip, instruction)
+            }
+            EmulationError::UndecodableBytes { offset, bytes } => {
+                write!(
+                    f,
+                    "Bytes at offset {:#x} don't decode to an instruction: {:02x} {:02x} {:02x}",
+                    offset, bytes[0], bytes[1], bytes[2]
+                )
+            }
+            EmulationError::TruncatedInstruction { offset, len } => {
+                write!(
+                    f,
+                    "Truncated instruction at offset {:#x}: {} bytes, 3 required",
+                    offset, len
+                )
+            }
             EmulationError::InvalidMemoryAddress { address } => {
                 write!(f, "Invalid memory address: {}", address)
             }
             EmulationError::InvalidSyscall { syscall } => {
                 write!(f, "Invalid syscall: {}", syscall)
             }
+            EmulationError::SyscallNotAllowed { syscall } => {
+                write!(f, "Syscall not allowed: {}", syscall)
+            }
+            EmulationError::SyscallBudgetExceeded => write!(f, "Syscall budget exceeded"),
+            EmulationError::StackOverflow => write!(f, "Stack overflow"),
+            EmulationError::StackUnderflow => write!(f, "Stack underflow"),
+            EmulationError::StepLimitExceeded => write!(f, "Step limit exceeded"),
+            EmulationError::Halted => write!(f, "The program halted"),
+            EmulationError::RegisterRegionUnbacked { size, required } => {
+                write!(
+                    f,
+                    "Memory is too small for the registers: {} bytes, {} required",
+                    size, required
+                )
+            }
+            EmulationError::InvalidConfig { error } => write!(f, "Invalid config: {}", error),
+            EmulationError::CodeTooLarge { size, max } => {
+                write!(
+                    f,
+                    "Code doesn't fit under the data page: {} bytes, at most {}",
+                    size, max
+                )
+            }
+            EmulationError::ExecutingData { address } => {
+                write!(f, "Instruction fetch past the end of code at: {}", address)
+            }
+            EmulationError::ProtectionFault { address, access } => {
+                write!(
+                    f,
+                    "Protection fault: {} at {} is not allowed",
+                    access, address
+                )
+            }
             EmulationError::OtherError => write!(f, "Other error occurred"),
         }
     }
@@ -38,14 +104,386 @@ impl fmt::Display for EmulationError {
 
 impl error::Error for EmulationError {}
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute"),
+        }
+    }
+}
+
+//Accesses a protected region allows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Prot {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Prot {
+    pub const NONE: Prot = Prot {
+        read: false,
+        write: false,
+        execute: false,
+    };
+    pub const READ: Prot = Prot {
+        read: true,
+        write: false,
+        execute: false,
+    };
+    pub const READ_WRITE: Prot = Prot {
+        read: true,
+        write: true,
+        execute: false,
+    };
+    pub const READ_EXECUTE: Prot = Prot {
+        read: true,
+        write: false,
+        execute: true,
+    };
+    pub const ALL: Prot = Prot {
+        read: true,
+        write: true,
+        execute: true,
+    };
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+            Access::Execute => self.execute,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunOutcome {
+    Halted,           // The program called exit
+    StepLimitReached, // Ran max_steps instructions without halting
+    Breakpoint(u8), // About to run the instruction at this ip, which has a breakpoint. Nothing ran yet
+}
+
+//One executed instruction with the registers right before it was fetched and right after it ran,
+//so the after of a record is the before of the next one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub before: RegisterSnapshot,
+    pub instruction: Instruction,
+    pub after: RegisterSnapshot,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StopReport {
+    pub outcome: RunOutcome,
+    pub ip: u8, // Next instruction to run
+    pub registers: RegisterSnapshot,
+    pub exit_code: Option<u8>, // Only set if the program called exit
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JumpEvent {
+    pub ip: u8,
+    pub flags_requested: u8,
+    pub flags_current: u8,
+    pub taken: bool,
+    pub target: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompareEvent {
+    pub left_reg: reg,
+    pub right_reg: reg,
+    pub left_val: u8,
+    pub right_val: u8,
+    pub flags_set: u8, // What the CMP wrote into f
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceEvent {
+    Jump(JumpEvent),
+    Compare(CompareEvent),
+}
+
+pub type RegisterDelta = (reg, u8, u8); // (register, old value, new value)
+
+//Register values at one point in time, by name rather than by the config's encoding
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RegisterSnapshot {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub s: u8,
+    pub i: u8,
+    pub f: u8,
+    pub extra: Vec<(&'static str, u8)>, // The config's extra registers, in the order it lists them
+}
+
+impl RegisterSnapshot {
+    fn named(&self) -> Vec<(&'static str, u8)> {
+        let mut named = vec![
+            ("a", self.a),
+            ("b", self.b),
+            ("c", self.c),
+            ("d", self.d),
+            ("s", self.s),
+            ("i", self.i),
+            ("f", self.f),
+        ];
+        named.extend_from_slice(&self.extra);
+        named
+    }
+
+    //Only the registers that differ, as (name, value in self, value in other). Meant for self = expected, other = actual
+    pub fn diff(&self, other: &RegisterSnapshot) -> Vec<(&'static str, u8, u8)> {
+        self.named()
+            .into_iter()
+            .zip(other.named())
+            .filter(|((_, expected), (_, actual))| expected != actual)
+            .map(|((name, expected), (_, actual))| (name, expected, actual))
+            .collect()
+    }
+}
+
+//A clone runs independently of the original, except for io: a MockIo and its clones share their buffers
+#[derive(Clone)]
 pub struct Emulator {
     mem: Vec<u8>,
     consts: VMConsts,
+    allowed_syscalls: Option<HashSet<u8>>, // None means every syscall is allowed
+    decode_cache: Option<HashMap<u8, Instruction>>, // Decoded instructions by ip, None when caching is off
+    decodes: usize, // Instructions decoded from memory when fetched, cache hits don't count
+    trace: bool,    // Print the registers and the instruction before executing it
+    halted: bool,
+    exit_code: Option<u8>, // What the program passed to exit, None if it hasn't called it
+    io: Box<dyn SyscallIo>,
+    events: Option<Vec<TraceEvent>>,      // None when not recording
+    instruction_ip: u8,                   // ip of the instruction being executed
+    write_watch: Option<(u16, Vec<u8>)>, // Address being watched and the ip of every instruction that wrote to it
+    protection: Vec<(Range<u16>, Prot)>, // Memory outside every range allows everything
+    full_trace: Option<Vec<TraceRecord>>, // None when not recording
+    syscall_budget: Option<usize>, // Syscalls left before they start failing, None for no limit
+    halt_on_eof: bool,
+    cycles: usize, // Instructions executed since the emulator was created
+    input_recording: Option<Vec<u8>>, // Every byte read from fd 0, None when not recording
+    input_replay: Option<VecDeque<u8>>, // When set, reads from fd 0 are served from here instead of io
+    breakpoints: HashSet<u8>,
+    breakpoint_hit: Option<(u8, usize)>, // (ip, cycles) of the last breakpoint stop, so resuming right there runs it
 }
 
 impl Emulator {
+    //Doesn't check the config, so a bad one only shows up once the program runs. try_new checks it up front
     pub fn new(mem: Vec<u8>, consts: VMConsts) -> Self {
-        Self { mem, consts }
+        Self {
+            mem,
+            consts,
+            allowed_syscalls: None,
+            decode_cache: None,
+            decodes: 0,
+            trace: false,
+            halted: false,
+            exit_code: None,
+            io: Box::new(LibcIo),
+            events: None,
+            instruction_ip: 0,
+            write_watch: None,
+            protection: vec![],
+            full_trace: None,
+            syscall_budget: None,
+            halt_on_eof: false,
+            cycles: 0,
+            input_recording: None,
+            input_replay: None,
+            breakpoints: HashSet::new(),
+            breakpoint_hit: None,
+        }
+    }
+
+    //Restricts what can be done to a range of memory. A range set later wins where it overlaps an earlier one
+    pub fn set_protection(&mut self, range: Range<u16>, prot: Prot) {
+        self.protection.push((range, prot));
+    }
+
+    fn check_access(&self, address: u16, access: Access) -> Result<(), EmulationError> {
+        let allowed = self
+            .protection
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&address))
+            .is_none_or(|(_, prot)| prot.allows(access));
+        if !allowed {
+            return Err(EmulationError::ProtectionFault { address, access });
+        }
+        Ok(())
+    }
+
+    //Lets the program make n more syscalls, the one after that fails with SyscallBudgetExceeded
+    pub fn set_syscall_budget(&mut self, n: usize) {
+        self.syscall_budget = Some(n);
+    }
+
+    //Starts (clearing anything recorded so far) or stops recording the bytes the program reads from fd 0
+    pub fn record_input(&mut self, enabled: bool) {
+        self.input_recording = if enabled { Some(vec![]) } else { None };
+    }
+
+    pub fn input_recording(&self) -> &[u8] {
+        self.input_recording.as_deref().unwrap_or_default()
+    }
+
+    //Serves reads from fd 0 from a recording instead of io, so a run can be repeated exactly.
+    //Reads past its end get EOF. Other fds still go to io
+    pub fn replay_input(&mut self, recording: &[u8]) {
+        self.input_replay = Some(recording.iter().copied().collect());
+    }
+
+    //run stops before executing the instruction at ip. Running again from there executes it and carries on
+    pub fn add_breakpoint(&mut self, ip: u8) {
+        self.breakpoints.insert(ip);
+    }
+
+    pub fn remove_breakpoint(&mut self, ip: u8) {
+        self.breakpoints.remove(&ip);
+    }
+
+    //Whether the program stopped, by exit, HALT or EOF with set_halt_on_eof. Nothing runs after that
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn exit_code(&self) -> Option<u8> {
+        self.exit_code
+    }
+
+    //Halts the VM when a read gets EOF, for programs that loop on input without checking for it
+    pub fn set_halt_on_eof(&mut self, enabled: bool) {
+        self.halt_on_eof = enabled;
+    }
+
+    //Replaces where the read and write syscalls go, e.g. with a MockIo to feed input and capture output
+    pub fn set_io(&mut self, io: Box<dyn SyscallIo>) {
+        self.io = io;
+    }
+
+    //Swaps the config while keeping memory as is, e.g. to try many configs against the same program
+    pub fn set_consts(&mut self, consts: VMConsts) -> Result<(), ConfigError> {
+        consts.validate()?;
+        self.consts = consts;
+        // Cached instructions were decoded with the old opcodes
+        if let Some(cache) = &mut self.decode_cache {
+            cache.clear();
+        }
+        Ok(())
+    }
+
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = enabled;
+    }
+
+    //Starts (clearing anything recorded so far) or stops recording TraceEvents
+    pub fn record_events(&mut self, enabled: bool) {
+        self.events = if enabled { Some(vec![]) } else { None };
+    }
+
+    pub fn events(&self) -> &[TraceEvent] {
+        self.events.as_deref().unwrap_or_default()
+    }
+
+    fn record_event(&mut self, event: TraceEvent) {
+        if let Some(events) = &mut self.events {
+            events.push(event);
+        }
+    }
+
+    //Starts (clearing anything recorded so far) or stops recording a TraceRecord for every instruction executed
+    pub fn set_full_trace(&mut self, enabled: bool) {
+        self.full_trace = if enabled { Some(vec![]) } else { None };
+    }
+
+    pub fn full_trace(&self) -> &[TraceRecord] {
+        self.full_trace.as_deref().unwrap_or_default()
+    }
+
+    //Caches decoded instructions by ip so loops don't decode the same bytes every iteration.
+    //Any write into the code region drops the cached instruction it overlaps
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = if enabled { Some(HashMap::new()) } else { None };
+    }
+
+    //How many fetched instructions had to be decoded from memory, i.e. weren't served by the decode cache
+    pub fn decodes(&self) -> usize {
+        self.decodes
+    }
+
+    //Same as new, but fails up front on an invalid config or if the memory doesn't reach the register region,
+    //instead of on the first register access
+    pub fn try_new(mem: Vec<u8>, consts: VMConsts) -> Result<Self, EmulationError> {
+        Self::check_config(&consts)?;
+        let required = consts
+            .registers
+            .mem_locations(consts.layout().reg_base)
+            .into_iter()
+            .max()
+            .map_or(0, |location| location as usize + 1);
+        if mem.len() < required {
+            return Err(EmulationError::RegisterRegionUnbacked {
+                size: mem.len(),
+                required,
+            });
+        }
+        Ok(Self::new(mem, consts))
+    }
+
+    // A config that can't read back its own encoding is a typo in the opcodes or indices
+    fn check_config(consts: &VMConsts) -> Result<(), EmulationError> {
+        consts
+            .validate()
+            .and_then(|_| consts.self_check())
+            .map_err(|error| EmulationError::InvalidConfig { error })
+    }
+
+    //Builds the flat memory from its parts: code at 0, data at the data page and zeroed registers, where the
+    //config's layout puts them. data is the page as a u8 offset sees it, bytes past the page's size are dropped.
+    //Fails on an invalid config or if the code doesn't fit under the data page
+    pub fn from_regions(
+        code: Vec<u8>,
+        data: [u8; 0x100],
+        consts: VMConsts,
+    ) -> Result<Self, EmulationError> {
+        // The layout decides how much of data gets copied, so it has to be checked first
+        Self::check_config(&consts)?;
+        let layout = consts.layout();
+        if code.len() > layout.code().end as usize {
+            return Err(EmulationError::CodeTooLarge {
+                size: code.len(),
+                max: layout.code().end as usize,
+            });
+        }
+        let ram = layout.ram();
+        let registers_end = consts
+            .registers
+            .mem_locations(layout.reg_base)
+            .into_iter()
+            .max()
+            .map_or(0, |location| location as usize + 1);
+        let mut mem = code;
+        mem.resize((ram.end as usize).max(registers_end), 0);
+        mem[ram.start as usize..ram.end as usize].copy_from_slice(&data[..ram.len()]);
+        Ok(Self::new(mem, consts))
+    }
+
+    //Restricts the syscalls the program can make. Any other syscall fails with SyscallNotAllowed instead of running
+    pub fn set_allowed_syscalls(&mut self, allowed: &[u8]) {
+        self.allowed_syscalls = Some(allowed.iter().copied().collect());
     }
 
     pub fn dump_registers(&self) -> Result<(), EmulationError> {
@@ -62,7 +500,34 @@ impl Emulator {
         Ok(())
     }
 
-    //reads a null terminated string starting at 0x300 (RAM) + offset
+    pub fn flags(&self) -> Result<FlagSet, EmulationError> {
+        let flags = self.read_register(self.consts.registers.f)?;
+        Ok(self.consts.cmp_flags.decode(flags))
+    }
+
+    pub fn set_flags(&mut self, flags: FlagSet) -> Result<(), EmulationError> {
+        self.write_register(self.consts.registers.f, self.consts.cmp_flags.encode(flags))
+    }
+
+    pub fn register_snapshot(&self) -> Result<RegisterSnapshot, EmulationError> {
+        let registers = self.consts.registers;
+        Ok(RegisterSnapshot {
+            a: self.read_register(registers.a)?,
+            b: self.read_register(registers.b)?,
+            c: self.read_register(registers.c)?,
+            d: self.read_register(registers.d)?,
+            s: self.read_register(registers.s)?,
+            i: self.read_register(registers.i)?,
+            f: self.read_register(registers.f)?,
+            extra: registers
+                .extra
+                .iter()
+                .map(|(name, register, _)| Ok((*name, self.read_register(*register)?)))
+                .collect::<Result<_, EmulationError>>()?,
+        })
+    }
+
+    //reads a null terminated string starting at the data page (RAM) + offset
     pub fn read_string(&self, offset: u8) -> Result<String, EmulationError> {
         let mut result = String::new();
         let mut current_offset = offset;
@@ -83,20 +548,138 @@ impl Emulator {
         let register_location = self
             .consts
             .registers
-            .reg_to_mem_location(register)
+            .reg_to_mem_location(register, self.consts.layout().reg_base)
             .ok_or(EmulationError::InvalidRegister { register })?;
         self.read_memory_raw(register_location)
     }
 
+    //Same as read_register, but NONE reads as 0 instead of failing
+    fn read_register_or_zero(&self, register: reg) -> Result<u8, EmulationError> {
+        if register == REG_NONE {
+            return Ok(0);
+        }
+        self.read_register(register)
+    }
+
+    pub fn ip(&self) -> Result<u8, EmulationError> {
+        self.read_register(self.consts.registers.i)
+    }
+
+    //Same as write_register, but refuses to point i at an instruction that isn't entirely inside the code region
+    //(code_region_end, or the data page if it isn't set), since the next fetch would run data
+    pub fn try_set_register(&mut self, register: reg, val: u8) -> Result<(), EmulationError> {
+        if register == self.consts.registers.i {
+            let code_end = self
+                .consts
+                .code_region_end
+                .unwrap_or(self.consts.layout().ram_base) as usize;
+            if val as usize * 3 + 3 > code_end {
+                return Err(EmulationError::ExecutingData {
+                    address: val as u16 * 3,
+                });
+            }
+        }
+        self.write_register(register, val)
+    }
+
+    //The next instruction fetched will be the one at ip
+    pub fn set_ip(&mut self, ip: u8) -> Result<(), EmulationError> {
+        self.write_register(self.consts.registers.i, ip)
+    }
+
     pub fn read_memory_raw(&self, location: u16) -> Result<u8, EmulationError> {
+        self.check_access(location, Access::Read)?;
         match self.mem.get(location as usize) {
             Some(val) => Ok(val.to_owned()),
             None => Err(EmulationError::InvalidMemoryAddress { address: location }),
         }
     }
 
+    //Reads the data page, 0x300-0x3FF by default. The offset is a u8 so it can't reach outside the page, and since
+    //guest arithmetic wraps at 8 bits a pointer past 0xFF wraps back to the start of the page instead of escaping it
     pub fn read_memory(&self, location: u8) -> Result<u8, EmulationError> {
-        self.read_memory_raw(location as u16 + 0x300)
+        self.read_memory_raw(self.data_address(location))
+    }
+
+    //Reads len bytes of the data page starting at offset. Fails if they don't all fit in the page
+    pub fn read_bytes(&self, offset: u8, len: u8) -> Result<Vec<u8>, EmulationError> {
+        let ram = self.consts.layout().ram();
+        if offset as usize + len as usize > ram.len() {
+            return Err(EmulationError::InvalidMemoryAddress { address: ram.end });
+        }
+        (0..len).map(|i| self.read_memory(offset + i)).collect()
+    }
+
+    //Writes data into the data page starting at offset, e.g. to preload a buffer. Nothing is written if it doesn't fit
+    pub fn write_bytes(&mut self, offset: u8, data: &[u8]) -> Result<(), EmulationError> {
+        let ram = self.consts.layout().ram();
+        if offset as usize + data.len() > ram.len() {
+            return Err(EmulationError::InvalidMemoryAddress { address: ram.end });
+        }
+        for (i, byte) in data.iter().enumerate() {
+            self.write_memory_raw(self.data_address(offset + i as u8), *byte)?;
+        }
+        Ok(())
+    }
+
+    //Pushes val the way STK does: s goes up by one, then val is stored at data page offset s.
+    //s is 8 bits, so a push with s at 0xFF fails instead of wrapping around to the bottom of the stack
+    pub fn push_value(&mut self, val: u8) -> Result<(), EmulationError> {
+        let s = self.consts.registers.s;
+        let sp = self
+            .read_register(s)?
+            .checked_add(1)
+            .ok_or(EmulationError::StackOverflow)?;
+        self.write_register(s, sp)?;
+        self.write_memory(sp, val)
+    }
+
+    //Pops the way STK does: reads data page offset s, then s goes down by one. Fails on an empty stack (s at 0)
+    pub fn pop_value(&mut self) -> Result<u8, EmulationError> {
+        let s = self.consts.registers.s;
+        let sp = self.read_register(s)?;
+        if sp == 0 {
+            return Err(EmulationError::StackUnderflow);
+        }
+        let val = self.read_memory(sp)?;
+        self.write_register(s, sp - 1)?;
+        Ok(val)
+    }
+
+    //Values on the stack from the bottom (offset 1) up to s. Offset 0 is below the first push, so it's never part of it
+    pub fn stack_contents(&self) -> Result<Vec<u8>, EmulationError> {
+        let sp = self.read_register(self.consts.registers.s)?;
+        self.read_bytes(1, sp)
+    }
+
+    //Absolute address a LDM, STM or STK would access with the current registers, None for anything else.
+    //STK reports where the push goes when it does both
+    pub fn effective_address(&self, instr: &Instruction) -> Result<Option<u16>, EmulationError> {
+        let offset = match *instr {
+            Instruction::Ldm { src, .. } => self.read_register(src)?,
+            Instruction::Stm { dst, .. } => self.read_register(dst)?,
+            Instruction::Stk { push, .. } if push != REG_NONE => {
+                self.read_register(self.consts.registers.s)?.wrapping_add(1)
+            }
+            Instruction::Stk { pop, .. } if pop != REG_NONE => {
+                self.read_register(self.consts.registers.s)?
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(self.data_address(offset)))
+    }
+
+    //A data page smaller than 0x100 bytes wraps the same way the full one does, just sooner
+    fn data_address(&self, location: u8) -> u16 {
+        let ram = self.consts.layout().ram();
+        let address = ram.start + location as u16 % ram.len().max(1) as u16;
+        debug_assert!(ram.contains(&address));
+        address
+    }
+
+    //Bytes from address to the end of the data page, how much a syscall buffer starting there can hold
+    fn data_page_room(&self, address: u16) -> usize {
+        self.consts.layout().ram().end.saturating_sub(address) as usize
     }
 
     //Returns new register value or None is register not found
@@ -104,7 +687,7 @@ impl Emulator {
         let register_location = self
             .consts
             .registers
-            .reg_to_mem_location(register)
+            .reg_to_mem_location(register, self.consts.layout().reg_base)
             .ok_or(EmulationError::InvalidRegister { register })?;
 
         self.write_memory_raw(register_location, val)
@@ -112,7 +695,18 @@ impl Emulator {
 
     //Writes to a raw memory address
     pub fn write_memory_raw(&mut self, location: u16, val: u8) -> Result<(), EmulationError> {
-        //Memory base is 0x300
+        self.check_access(location, Access::Write)?;
+        //fetch_next_instruction reads up to ip 0xff, wherever the data page starts
+        if location < 0x300 {
+            if let Some(cache) = &mut self.decode_cache {
+                cache.remove(&((location / 3) as u8));
+            }
+        }
+        if let Some((watched, writers)) = &mut self.write_watch {
+            if *watched == location {
+                writers.push(self.instruction_ip);
+            }
+        }
         match self.mem.get_mut(location as usize) {
             Some(mem) => {
                 *mem = val;
@@ -122,10 +716,97 @@ impl Emulator {
         }
     }
 
-    //Actual writable memory is 0x300-0x400 so any actual write_memory is addr + 0x300 offset
+    //Actual writable memory is the data page (0x300-0x400 by default) so any actual write_memory is addr + its base.
+    //Same page rules as read_memory
     pub fn write_memory(&mut self, location: u8, val: u8) -> Result<(), EmulationError> {
-        println!("Writing memory at offset {}", location);
-        self.write_memory_raw(location as u16 + 0x300, val)
+        self.trace_log(format_args!("Writing memory at offset {}", location));
+        self.write_memory_raw(self.data_address(location), val)
+    }
+
+    //Hexdump of all of memory split into code, data and registers, with real addresses as offsets
+    pub fn hexdump_all(&self) -> String {
+        let layout = self.consts.layout();
+        let sections = [
+            ("code", layout.code()),
+            ("data", layout.ram()),
+            ("registers", layout.registers()),
+        ];
+        let mut dump = String::new();
+        for (name, range) in sections {
+            let start = (range.start as usize).min(self.mem.len());
+            let end = (range.end as usize).min(self.mem.len());
+            dump.push_str(&format!(
+                "== {} ({:#06x}-{:#06x}) ==\n",
+                name, range.start, range.end
+            ));
+            dump.push_str(&hex_dump(&self.mem[start..end], start));
+        }
+        dump
+    }
+
+    //Every instruction of the loaded program with its address, from address 0 up to the first bytes that
+    //don't decode or the end of the code region
+    pub fn disassemble_code(&self) -> Result<Vec<(u16, Instruction)>, EmulationError> {
+        let end = (self.consts.layout().code().end as usize).min(self.mem.len());
+        let mut instructions = vec![];
+        for (i, instruction_bytes) in self.mem[..end].chunks_exact(3).enumerate() {
+            match Instruction::from_bytes(
+                instruction_bytes,
+                self.consts.instruction_indices,
+                self.consts.opcodes,
+            ) {
+                Some(instruction) => instructions.push(((i * 3) as u16, instruction)),
+                None => break,
+            }
+        }
+        Ok(instructions)
+    }
+
+    //Listing of the loaded program, from address 0 up to the first bytes that don't decode or the data page
+    pub fn disassemble(&self) -> Result<String, EmulationError> {
+        let instructions = self.disassemble_code()?.len();
+        disassembler::disassemble(&self.mem[..instructions * 3], self.consts)
+    }
+
+    //Checks that the code region decodes, without running anything. The region ends at code_region_end if set
+    //and at the data page otherwise. Zeroed memory after the last instruction is padding and isn't checked
+    pub fn verify_program(&self) -> Result<(), EmulationError> {
+        let end = self
+            .consts
+            .code_region_end
+            .unwrap_or(self.consts.layout().ram_base) as usize;
+        let code = &self.mem[..self.mem.len().min(end)];
+        let used = code
+            .iter()
+            .rposition(|byte| *byte != 0)
+            .map_or(0, |last| (last / 3 + 1) * 3)
+            .min(code.len());
+        for (ip, instruction_bytes) in code[..used].chunks(3).enumerate() {
+            // ip is a u8, so nothing past 0x300 can ever be fetched
+            let ip = u8::try_from(ip).map_err(|_| EmulationError::CodeTooLarge {
+                size: used,
+                max: 0x300,
+            })?;
+            if instruction_bytes.len() < 3 {
+                return Err(EmulationError::TruncatedInstruction {
+                    offset: ip as usize * 3,
+                    len: instruction_bytes.len(),
+                });
+            }
+            if Instruction::from_bytes(
+                instruction_bytes,
+                self.consts.instruction_indices,
+                self.consts.opcodes,
+            )
+            .is_none()
+            {
+                return Err(EmulationError::InvalidInstructionAt {
+                    ip,
+                    instruction: instruction_bytes[self.consts.instruction_indices.opcode],
+                });
+            }
+        }
+        Ok(())
     }
 
     pub fn parse_instruction(
@@ -137,6 +818,11 @@ impl Emulator {
             self.consts.instruction_indices,
             self.consts.opcodes,
         ) {
+            // The VM never sees the bits outside the mask, so neither should traces or the decode cache
+            Some(Instruction::Imm { dst, val }) => Ok(Instruction::Imm {
+                dst,
+                val: val & self.consts.immediate_mask.unwrap_or(0xFF),
+            }),
             Some(instruction) => Ok(instruction),
             None => Err(EmulationError::InvalidInstruction {
                 instruction: instruction_bytes[0],
@@ -144,30 +830,258 @@ impl Emulator {
         }
     }
 
+    //Executes instructions until the program exits or max_steps instructions have run
+    pub fn run(&mut self, max_steps: usize) -> Result<RunOutcome, EmulationError> {
+        self.run_with_hook(max_steps, |_| Ok(()))
+    }
+
+    //Same as run, but calls hook before every instruction, e.g. to patch registers mid-run.
+    //An error from the hook stops the run. Use try_set_register to change registers safely
+    pub fn run_with_hook<F>(
+        &mut self,
+        max_steps: usize,
+        mut hook: F,
+    ) -> Result<RunOutcome, EmulationError>
+    where
+        F: FnMut(&mut Emulator) -> Result<(), EmulationError>,
+    {
+        for _ in 0..max_steps {
+            if self.halted {
+                return Ok(RunOutcome::Halted);
+            }
+            if !self.breakpoints.is_empty() {
+                let ip = self.ip()?;
+                // Anything that ran since the stop, in this run or by stepping by hand, makes it stop again
+                if self.breakpoints.contains(&ip) && self.breakpoint_hit != Some((ip, self.cycles))
+                {
+                    self.breakpoint_hit = Some((ip, self.cycles));
+                    return Ok(RunOutcome::Breakpoint(ip));
+                }
+            }
+            hook(self)?;
+            self.execute_next_instruction()?;
+        }
+        if self.halted {
+            return Ok(RunOutcome::Halted);
+        }
+        Ok(RunOutcome::StepLimitReached)
+    }
+
+    //Runs until about to execute an ip that already ran, i.e. the program looped, and returns that ip.
+    //Fails with StepLimitExceeded if it doesn't happen within max_steps, or Halted if the program stops first
+    pub fn run_until_ip_repeat(&mut self, max_steps: usize) -> Result<u8, EmulationError> {
+        let mut executed = HashSet::new();
+        for _ in 0..max_steps {
+            if self.halted {
+                return Err(EmulationError::Halted);
+            }
+            let ip = self.ip()?;
+            if !executed.insert(ip) {
+                return Ok(ip);
+            }
+            self.execute_next_instruction()?;
+        }
+        if self.halted {
+            return Err(EmulationError::Halted);
+        }
+        Err(EmulationError::StepLimitExceeded)
+    }
+
+    //Runs until register holds value and returns how many instructions that took (0 if it already does).
+    //Fails with StepLimitExceeded if it doesn't happen within max_steps, or Halted if the program stops first
+    pub fn run_until_register_eq(
+        &mut self,
+        register: reg,
+        value: u8,
+        max_steps: usize,
+    ) -> Result<usize, EmulationError> {
+        for steps in 0..=max_steps {
+            if self.read_register(register)? == value {
+                return Ok(steps);
+            }
+            if steps == max_steps {
+                break;
+            }
+            if self.halted {
+                return Err(EmulationError::Halted);
+            }
+            self.execute_next_instruction()?;
+        }
+        Err(EmulationError::StepLimitExceeded)
+    }
+
+    //Same as run, plus the state a front end needs to show where it stopped
+    pub fn run_to_stop(&mut self, max_steps: usize) -> Result<StopReport, EmulationError> {
+        let outcome = self.run(max_steps)?;
+        Ok(StopReport {
+            outcome,
+            ip: self.ip()?,
+            registers: self.register_snapshot()?,
+            exit_code: self.exit_code,
+        })
+    }
+
+    //Same as run_with_io with no input, returning only what the program wrote to fd 1
+    pub fn run_capturing(
+        &mut self,
+        max_steps: usize,
+    ) -> Result<(RunOutcome, Vec<u8>), EmulationError> {
+        let (outcome, stdout, _) = self.run_with_io(&[], max_steps)?;
+        Ok((outcome, stdout))
+    }
+
+    //Same as run, with fd 0 served from stdin and whatever goes to fd 1 and 2 returned as (stdout, stderr).
+    //The io set before is put back afterwards
+    pub fn run_with_io(
+        &mut self,
+        stdin: &[u8],
+        max_steps: usize,
+    ) -> Result<(RunOutcome, Vec<u8>, Vec<u8>), EmulationError> {
+        let io = MockIo::with_stdin(stdin);
+        let previous = std::mem::replace(&mut self.io, Box::new(io.clone()));
+        let outcome = self.run(max_steps);
+        self.io = previous;
+        Ok((outcome?, io.stdout(), io.stderr()))
+    }
+
+    //Same as run, but returns the ip of every instruction that wrote to the given data page offset, in order
+    pub fn run_tracking_writes(
+        &mut self,
+        offset: u8,
+        max_steps: usize,
+    ) -> Result<Vec<u8>, EmulationError> {
+        self.write_watch = Some((self.data_address(offset), vec![]));
+        let outcome = self.run(max_steps);
+        let writers = self
+            .write_watch
+            .take()
+            .map(|(_, writers)| writers)
+            .unwrap_or_default();
+        outcome.map(|_| writers)
+    }
+
     pub fn execute_next_instruction(&mut self) -> Result<(), EmulationError> {
-        let ip = self.read_register(self.consts.registers.i)? as usize;
+        // A halted program stays halted, stepping it again doesn't run what comes after the exit
+        if self.halted {
+            return Err(EmulationError::Halted);
+        }
+        let before = match self.full_trace {
+            Some(_) => Some(self.register_snapshot()?),
+            None => None,
+        };
+        let instruction = self.fetch_next_instruction()?;
+        self.interpret_instruction(instruction)?;
+        if let Some(before) = before {
+            let after = self.register_snapshot()?;
+            if let Some(records) = &mut self.full_trace {
+                records.push(TraceRecord {
+                    before,
+                    instruction,
+                    after,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    //Executes the next instruction and returns it along with every (register, old, new) it changed.
+    //The ip moving on to the next instruction doesn't count as a change, only jumps and explicit writes do
+    pub fn step_with_delta(&mut self) -> Result<(Instruction, Vec<RegisterDelta>), EmulationError> {
+        if self.halted {
+            return Err(EmulationError::Halted);
+        }
+        let instruction = self.fetch_next_instruction()?;
+
+        let registers: Vec<reg> = self
+            .consts
+            .registers
+            .all()
+            .into_iter()
+            .chain(
+                self.consts
+                    .registers
+                    .extra
+                    .iter()
+                    .map(|(_, register, _)| *register),
+            )
+            .collect();
+        let before = registers
+            .iter()
+            .map(|register| self.read_register(*register))
+            .collect::<Result<Vec<u8>, EmulationError>>()?;
+
+        self.interpret_instruction(instruction)?;
+
+        let mut delta = vec![];
+        for (register, old) in registers.iter().zip(before) {
+            let new = self.read_register(*register)?;
+            if new != old {
+                delta.push((*register, old, new));
+            }
+        }
+        Ok((instruction, delta))
+    }
+
+    //Reads and decodes the instruction at ip, leaving ip pointing at the one after it
+    fn fetch_next_instruction(&mut self) -> Result<Instruction, EmulationError> {
+        let ip = self.ip()? as usize;
+
+        // Running into the data is usually a runaway program rather than code stored there on purpose
+        if let Some(code_region_end) = self.consts.code_region_end {
+            if ip * 3 + 3 > code_region_end as usize {
+                return Err(EmulationError::ExecutingData {
+                    address: (ip * 3) as u16,
+                });
+            }
+        }
+
+        for address in ip * 3..ip * 3 + 3 {
+            self.check_access(address as u16, Access::Execute)?;
+        }
 
         // Check for potential overflow when incrementing `ip`
         let incremented_ip = ip.checked_add(1).ok_or(EmulationError::OtherError)?;
-        self.write_register(self.consts.registers.i, incremented_ip as u8)?;
+        self.set_ip(incremented_ip as u8)?;
+        self.instruction_ip = ip as u8;
+
+        let instruction = match self
+            .decode_cache
+            .as_ref()
+            .and_then(|cache| cache.get(&(ip as u8)))
+        {
+            Some(instruction) => *instruction,
+            None => {
+                let instruction = self.decode_at(ip)?;
+                self.decodes += 1;
+                if let Some(cache) = &mut self.decode_cache {
+                    cache.insert(ip as u8, instruction);
+                }
+                instruction
+            }
+        };
+
+        Ok(instruction)
+    }
 
+    //Decodes the instruction at ip straight from memory, bypassing the decode cache
+    fn decode_at(&self, ip: usize) -> Result<Instruction, EmulationError> {
         // Fetch the instruction bytes and handle errors
-        let instruction_bytes = self.mem.get(ip * 3..(ip * 3) + 3).ok_or_else(|| {
-            EmulationError::InvalidMemoryAddress {
+        let instruction_bytes: &[u8; 3] = self
+            .mem
+            .get(ip * 3..(ip * 3) + 3)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(EmulationError::InvalidMemoryAddress {
                 address: (ip * 3) as u16,
-            }
-        })?;
+            })?;
 
-        // Match on the instruction bytes
-        match Instruction::from_bytes(
-            instruction_bytes,
-            self.consts.instruction_indices,
-            self.consts.opcodes,
-        ) {
-            Some(instruction) => self.interpret_instruction(instruction),
-            None => Err(EmulationError::InvalidInstruction {
-                instruction: instruction_bytes[0],
-            }),
+        // Goes through parse_instruction so the fetched instruction is already masked
+        self.parse_instruction(instruction_bytes)
+    }
+
+    //Only prints when tracing is on
+    fn trace_log(&self, message: fmt::Arguments) {
+        if self.trace {
+            println!("{}", message);
         }
     }
 
@@ -175,34 +1089,72 @@ impl Emulator {
         &mut self,
         instruction: Instruction,
     ) -> Result<(), EmulationError> {
-        self.dump_registers()?;
-        instruction.pretty_print(self.consts.registers);
+        if self.trace {
+            // Tracing is best effort, a register that can't be read shouldn't stop the instruction from running
+            let _ = self.dump_registers();
+            instruction.pretty_print(self.consts.registers);
+        }
+        self.execute(instruction)?;
+        self.cycles += 1;
+        Ok(())
+    }
+
+    //How many instructions ran to completion so far, over every run. The difference before and after a run
+    //is how much of its budget it used
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    //Runs a single instruction as if it had been fetched, without touching ip or printing anything unless
+    //tracing is on. Handy to test what an instruction does without assembling a program for it
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), EmulationError> {
         match instruction {
-            Instruction::Imm { dst, val } => self.write_register(dst, val),
-            Instruction::Add { dst, src } => self.write_register(
-                dst,
-                self.read_register(dst)?
-                    .wrapping_add(self.read_register(src)?),
-            ),
+            Instruction::Imm { dst, val } => {
+                self.write_register(dst, val & self.consts.immediate_mask.unwrap_or(0xFF))
+            }
+            Instruction::Add { dst, src } => {
+                let value = match self.consts.add_operand {
+                    AddOperandMode::Register => self.read_register(src)?,
+                    AddOperandMode::Immediate => src,
+                };
+                let result = self.read_register(dst)?.wrapping_add(value);
+                self.write_register(dst, result)?;
+                if self.consts.flags_on_arith {
+                    // Only the zero flag is about the result, the rest are left as the last CMP set them
+                    let zero = self.consts.cmp_flags.zero;
+                    let flags = self.read_register(self.consts.registers.f)? & !zero;
+                    let flags = if result == 0 { flags | zero } else { flags };
+                    self.write_register(self.consts.registers.f, flags)?;
+                }
+                Ok(())
+            }
             Instruction::Stk { pop, push } => {
+                // Push always happens before pop, same as the original VM, so a combined STK
+                // pops the value it just pushed and leaves the stack pointer where it was
                 if push != 0 {
-                    // Increase stack pointer
-                    self.write_register(
-                        self.consts.registers.s,
-                        self.read_register(self.consts.registers.s)? + 1,
-                    )?;
+                    // Increase stack pointer. Unlike push_value, the register is read after this,
+                    // so STK NONE s pushes the new stack pointer like the original VM does
+                    let sp = self
+                        .read_register(self.consts.registers.s)?
+                        .checked_add(1)
+                        .ok_or(EmulationError::StackOverflow)?;
+                    self.write_register(self.consts.registers.s, sp)?;
                     let val = self.read_register(push)?; //Read the register we are going to push
-                    self.write_memory(self.read_register(self.consts.registers.s)?, val)?;
+                    self.write_memory(sp, val)?;
                     // Write the value from the register at the stack pointer
                 }
                 if pop != 0 {
-                    // Increase stack pointer
-                    let val = self.read_memory(self.read_register(self.consts.registers.s)?)?; // Read the memory value stored at the stack pointer
+                    let sp = self.read_register(self.consts.registers.s)?;
+                    if sp == 0 {
+                        return Err(EmulationError::StackUnderflow);
+                    }
+                    let val = self.read_memory(sp)?; // Read the memory value stored at the stack pointer
                     self.write_register(pop, val)?; //Write that value to the register
-                                                    // Decrease the stack pointer
+                                                    // Decrease the stack pointer. STK s NONE just
+                                                    // overwrote it, and wraps the way a u8 does in C
                     self.write_register(
                         self.consts.registers.s,
-                        self.read_register(self.consts.registers.s)? - 1,
+                        self.read_register(self.consts.registers.s)?.wrapping_sub(1),
                     )?;
                 }
                 Ok(())
@@ -217,13 +1169,9 @@ impl Emulator {
             }
             Instruction::Cmp { left, right } => {
                 let mut new_flags: u8 = 0;
-                let left_value = self.read_register(left)?;
-                let right_value = self.read_register(right)?;
-
-                println!(
-                    "\nbig boy comp: Left: {}, right: {}\n",
-                    left_value, right_value
-                );
+                // NONE compares as 0, so CMP x NONE tests x for zero
+                let left_value = self.read_register_or_zero(left)?;
+                let right_value = self.read_register_or_zero(right)?;
 
                 if (left_value == 0) && (right_value == 0) {
                     new_flags |= self.consts.cmp_flags.zero;
@@ -241,109 +1189,1392 @@ impl Emulator {
                     //This is an else on the ghidra decompilation, which makes sense since if it's not equal it must be different, but making it explicit looks better
                     new_flags |= self.consts.cmp_flags.not_equals
                 }
+                self.record_event(TraceEvent::Compare(CompareEvent {
+                    left_reg: left,
+                    right_reg: right,
+                    left_val: left_value,
+                    right_val: right_value,
+                    flags_set: new_flags,
+                }));
 
                 self.write_register(self.consts.registers.f, new_flags)
             }
             Instruction::Jmp { flags, dst } => {
-                if flags == 0 || self.read_register(self.consts.registers.f)? & flags != 0 {
+                let flags_current = self.read_register(self.consts.registers.f)?;
+                let taken = flags == 0 || flags_current & flags != 0;
+                if self.events.is_some() {
+                    // A jump that isn't taken never reads its target, so don't fail because of it
+                    let target = if taken {
+                        self.read_register(dst)?
+                    } else {
+                        self.read_register(dst).unwrap_or_default()
+                    };
+                    self.record_event(TraceEvent::Jump(JumpEvent {
+                        ip: self.instruction_ip,
+                        flags_requested: flags,
+                        flags_current,
+                        taken,
+                        target,
+                    }));
+                }
+                if taken {
                     //take the jump
                     return self.write_register(self.consts.registers.i, self.read_register(dst)?);
                 }
                 Ok(())
             }
+            Instruction::Halt => {
+                self.halted = true;
+                Ok(())
+            }
             Instruction::Sys { num, dst } => {
+                if let Some(allowed) = &self.allowed_syscalls {
+                    if !allowed.contains(&num) {
+                        return Err(EmulationError::SyscallNotAllowed { syscall: num });
+                    }
+                }
+                if let Some(budget) = &mut self.syscall_budget {
+                    if *budget == 0 {
+                        return Err(EmulationError::SyscallBudgetExceeded);
+                    }
+                    *budget -= 1;
+                }
+                let abi = self.consts.syscall_abi();
                 match num {
                     num if num == self.consts.syscalls.write => {
                         // write
 
-                        let fd = self.read_register(self.consts.registers.a)?;
-                        let origin_offset = self.read_register(self.consts.registers.b)?;
-                        let mut n_bytes = self.read_register(self.consts.registers.c)? as usize;
-                        let max_bytes = 0x100 - origin_offset as usize;
-
-                        // Ensure we don't write after our memory region, which goes up to 0x400
-                        n_bytes = n_bytes.min(max_bytes);
-
-                        let buffer = &self.mem[0x300 + origin_offset as usize..][..n_bytes];
-
-                        println!("Attempting to write '{:#?}' to fd {}", buffer, fd);
-
-                        unsafe {
-                            let num_written = libc::write(
-                                fd.into(),
-                                buffer.as_ptr() as *mut std::ffi::c_void,
-                                n_bytes,
-                            ) as isize; // Changed to isize to match libc::write return type
-                            if num_written >= 0 {
-                                println!("Wrote {} bytes into fd {}", num_written, fd);
-                                self.write_register(dst, num_written as u8)?
-                            } else {
-                                println!("Error writing into fd {}", fd)
+                        let fd = self.read_register(abi.arg1)?;
+                        let origin_offset = self.read_register(abi.arg2)?;
+                        let mut n_bytes = self.read_register(abi.arg3)? as usize;
+                        let start = self.data_address(origin_offset);
+
+                        // Ensure we don't write after our memory region, the end of the data page
+                        n_bytes = n_bytes.min(self.data_page_room(start));
+
+                        // Read byte by byte so protection applies to the buffer like it does to LDM
+                        let buffer = (start..start + n_bytes as u16)
+                            .map(|address| self.read_memory_raw(address))
+                            .collect::<Result<Vec<u8>, EmulationError>>()?;
+
+                        self.trace_log(format_args!(
+                            "Attempting to write '{:#?}' to fd {}",
+                            buffer, fd
+                        ));
+
+                        // A write can take less than the whole buffer (e.g. a full pipe), keep going with the rest.
+                        // An error after some progress still reports what was written, like a POSIX write loop
+                        let mut num_written: isize = 0;
+                        while (num_written as usize) < n_bytes {
+                            let written = self.io.write(fd, &buffer[num_written as usize..]);
+                            if written < 0 && num_written == 0 {
+                                num_written = written;
                             }
+                            if written <= 0 {
+                                break;
+                            }
+                            num_written += written;
+                        }
+                        if num_written >= 0 {
+                            self.trace_log(format_args!(
+                                "Wrote {} bytes into fd {}",
+                                num_written, fd
+                            ));
+                            self.write_register(dst, num_written as u8)?
+                        } else {
+                            self.trace_log(format_args!("Error writing into fd {}", fd))
                         }
                         Ok(())
                     }
                     num if num == self.consts.syscalls.read_memory => {
                         //read_memory
-                        println!("[s] ... read_memory");
+                        self.trace_log(format_args!("[s] ... read_memory"));
 
-                        let fd = self.read_register(self.consts.registers.a)?;
-                        let dest_offset = self.read_register(self.consts.registers.b)?;
-                        let n_bytes = self.read_register(self.consts.registers.c)? as usize; // Use usize for buffer size
-                        let max_bytes = 0x100 - dest_offset as usize;
-
-                        let n_bytes = n_bytes.min(max_bytes); // Use min function for clarity
+                        let fd = self.read_register(abi.arg1)?;
+                        let dest_offset = self.read_register(abi.arg2)?;
+                        let n_bytes = self.read_register(abi.arg3)? as usize; // Use usize for buffer size
+                        let start = self.data_address(dest_offset);
+                        let n_bytes = n_bytes.min(self.data_page_room(start)); // Use min function for clarity
 
                         let mut buffer: Vec<u8> = vec![0; n_bytes]; // Initialize buffer with zeros directly
 
-                        unsafe {
-                            let num_read = libc::read(
-                                fd.into(),
-                                buffer.as_mut_ptr() as *mut std::ffi::c_void,
-                                n_bytes,
-                            );
-
-                            if num_read >= 0 {
-                                let num_read = num_read as usize; // Cast to usize for indexing
-                                for i in 0..num_read {
-                                    self.write_memory(dest_offset + i as u8, buffer[i])?;
+                        let num_read = match &mut self.input_replay {
+                            Some(replay) if fd == 0 => {
+                                let count = n_bytes.min(replay.len());
+                                for (byte, replayed) in buffer.iter_mut().zip(replay.drain(..count))
+                                {
+                                    *byte = replayed;
                                 }
-                                println!(
-                                    "Read {} bytes from fd {} into offset {}",
-                                    num_read, fd, dest_offset
-                                );
-                            } else {
-                                println!("Error reading from fd {}", fd);
+                                count as isize
+                            }
+                            _ => self.io.read(fd, &mut buffer),
+                        };
+                        if num_read >= 0 {
+                            let num_read = num_read as usize; // Cast to usize for indexing
+                            if let (Some(recording), 0) = (&mut self.input_recording, fd) {
+                                recording.extend_from_slice(&buffer[..num_read]);
+                            }
+                            for (address, byte) in (start..).zip(&buffer[..num_read]) {
+                                self.write_memory_raw(address, *byte)?;
+                            }
+                            self.trace_log(format_args!(
+                                "Read {} bytes from fd {} into offset {}",
+                                num_read, fd, dest_offset
+                            ));
+                            // 0 tells the program it hit EOF, same as libc
+                            self.write_register(dst, num_read as u8)?;
+                            if num_read == 0 && n_bytes > 0 && self.halt_on_eof {
+                                self.halted = true;
                             }
-                            Ok(())
+                        } else {
+                            self.trace_log(format_args!("Error reading from fd {}", fd));
                         }
+                        Ok(())
+                    }
+                    // The code is the first argument, a unless the config says otherwise
+                    num if Some(num) == self.consts.syscalls.exit => {
+                        self.exit_code = Some(self.read_register(abi.arg1)?);
+                        self.halted = true;
+                        Ok(())
                     }
                     num if num == self.consts.syscalls.open => {
                         //Open
-                        println!("[s] ... open");
-                        let path: String =
-                            self.read_string(self.read_register(self.consts.registers.a)?)?;
-                        let flags = self.read_register(self.consts.registers.b)?;
-                        let mode = self.read_register(self.consts.registers.c)?;
-                        unsafe {
-                            let path_c = CString::new(path).unwrap();
-                            let fd: u8 = match libc::open(
-                                path_c.as_ptr().into(),
-                                flags.into(),
-                                mode as c_int,
-                            )
-                            .try_into()
-                            {
-                                Ok(fd) => fd,
-                                Err(_err) => return Err(EmulationError::OtherError),
-                            };
-                            self.write_register(dst, fd)?;
+                        self.trace_log(format_args!("[s] ... open"));
+                        let path: String = self.read_string(self.read_register(abi.arg1)?)?;
+                        let flags = self.read_register(abi.arg2)?;
+                        let mode = self.read_register(abi.arg3)?;
+                        let fd: u8 = match self.io.open(&path, flags, mode).try_into() {
+                            Ok(fd) => fd,
+                            Err(_err) => return Err(EmulationError::OtherError),
                         };
-                        Ok(())
+                        self.write_register(dst, fd)
                     }
-                    _ => return Err(EmulationError::InvalidSyscall { syscall: num }),
+                    _ => Err(EmulationError::InvalidSyscall { syscall: num }),
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{
+        arch::MemoryLayout,
+        assembler::assemble,
+        io::MockIo,
+        testing::{consts, emulator, emulator_with},
+    };
+
+    fn step(emulator: &mut Emulator, n: usize) {
+        for _ in 0..n {
+            emulator.execute_next_instruction().unwrap();
+        }
+    }
+
+    #[test]
+    fn stk_push_only() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM a 42\nSTK NONE a");
+        step(&mut e, 2);
+        assert_eq!(e.read_register(regs.s).unwrap(), 1);
+        assert_eq!(e.read_memory(1).unwrap(), 42);
+        assert_eq!(e.read_register(regs.a).unwrap(), 42);
+    }
+
+    #[test]
+    fn stk_pop_only() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM s 3\nSTK c NONE");
+        e.write_memory(3, 0x99).unwrap();
+        step(&mut e, 2);
+        assert_eq!(e.read_register(regs.s).unwrap(), 2);
+        assert_eq!(e.read_register(regs.c).unwrap(), 0x99);
+        assert_eq!(e.read_memory(3).unwrap(), 0x99);
+    }
+
+    #[test]
+    fn stk_push_then_pop_in_one_instruction() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM a 7\nIMM s 4\nSTK b a");
+        e.write_memory(4, 0x55).unwrap();
+        step(&mut e, 3);
+        //The pop reads the value just pushed, so the prior top is untouched
+        assert_eq!(e.read_register(regs.s).unwrap(), 4);
+        assert_eq!(e.read_register(regs.b).unwrap(), 7);
+        assert_eq!(e.read_memory(5).unwrap(), 7);
+        assert_eq!(e.read_memory(4).unwrap(), 0x55);
+    }
+
+    #[test]
+    fn syscall_outside_the_allowed_set_is_blocked() {
+        let syscalls = consts().syscalls;
+        let io = MockIo::new();
+        let mut e = emulator("IMM a 1\nIMM b 0\nIMM c 1\nSYS 4 d\nSYS 8 d");
+        e.set_io(Box::new(io.clone()));
+        e.set_allowed_syscalls(&[syscalls.write]);
+        step(&mut e, 4);
+        assert_eq!(io.stdout(), vec![0]);
+        assert!(matches!(
+            e.execute_next_instruction(),
+            Err(EmulationError::SyscallNotAllowed { syscall }) if syscall == syscalls.open
+        ));
+    }
+
+    const COUNT_TO_FIVE: &str =
+        "IMM a 0\nIMM b 1\nIMM c 5\nloop: ADD a b\nCMP a c\nJMP L loop\nSYS 0x20 d";
+
+    #[test]
+    fn decode_cache_gives_the_same_results() {
+        let mut uncached = emulator(COUNT_TO_FIVE);
+        let mut cached = emulator(COUNT_TO_FIVE);
+        cached.set_decode_cache(true);
+        assert_eq!(uncached.run(100).unwrap(), RunOutcome::Halted);
+        assert_eq!(cached.run(100).unwrap(), RunOutcome::Halted);
+        assert_eq!(cached.exit_code(), Some(5));
+        assert_eq!(cached.exit_code(), uncached.exit_code());
+        assert_eq!(cached.cycles(), uncached.cycles());
+        assert_eq!(
+            cached.register_snapshot().unwrap(),
+            uncached.register_snapshot().unwrap()
+        );
+    }
+
+    #[test]
+    fn decode_cache_decodes_each_instruction_once() {
+        let mut uncached = emulator(COUNT_TO_FIVE);
+        let mut cached = emulator(COUNT_TO_FIVE);
+        cached.set_decode_cache(true);
+        uncached.run(100).unwrap();
+        cached.run(100).unwrap();
+        assert_eq!(uncached.decodes(), uncached.cycles());
+        //8 instructions once JMP L loop is expanded, every one decoded the first time it runs
+        assert_eq!(cached.decodes(), 8);
+        assert!(cached.decodes() < uncached.decodes());
+    }
+
+    #[test]
+    fn decode_cache_sees_writes_to_a_data_page_under_the_code() {
+        let mut ctx = consts();
+        ctx.memory_layout = Some(MemoryLayout {
+            ram_base: 0,
+            ram_size: 0x100,
+            reg_base: 0x400,
+        });
+        let mut e = emulator_with("IMM a 1", ctx);
+        e.set_decode_cache(true);
+        step(&mut e, 1);
+        // The data page starts at 0, so offset 2 is IMM's value byte
+        e.write_memory(2, 7).unwrap();
+        e.set_ip(0).unwrap();
+        step(&mut e, 1);
+        assert_eq!(e.read_register(ctx.registers.a).unwrap(), 7);
+    }
+
+    #[test]
+    fn try_new_rejects_a_shared_opcode() {
+        let mut ctx = consts();
+        ctx.opcodes.stm = ctx.opcodes.ldm;
+        assert!(matches!(
+            Emulator::try_new(vec![0; 0x407], ctx),
+            Err(EmulationError::InvalidConfig {
+                error: ConfigError::EncodingMismatch { .. }
+            })
+        ));
+        // new leaves the check to whoever picked the config
+        Emulator::new(vec![0; 0x407], ctx);
+    }
+
+    #[test]
+    fn try_new_rejects_a_special_scratch_register() {
+        let mut ctx = consts();
+        ctx.scratch = Some(ctx.registers.s);
+        assert!(matches!(
+            Emulator::try_new(vec![0; 0x407], ctx),
+            Err(EmulationError::InvalidConfig {
+                error: ConfigError::InvalidScratchRegister { .. }
+            })
+        ));
+    }
+
+    #[test]
+    fn memory_without_the_register_file_is_rejected_up_front() {
+        assert!(matches!(
+            Emulator::try_new(vec![0; 0x400], consts()),
+            Err(EmulationError::RegisterRegionUnbacked {
+                size: 0x400,
+                required: 0x407
+            })
+        ));
+        assert!(Emulator::try_new(vec![0; 0x407], consts()).is_ok());
+    }
+
+    #[test]
+    fn step_with_delta_reports_only_the_changed_register() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM a 2\nIMM b 3\nADD a b");
+        step(&mut e, 2);
+        let (instruction, delta) = e.step_with_delta().unwrap();
+        assert_eq!(
+            instruction,
+            Instruction::Add {
+                dst: regs.a,
+                src: regs.b
+            }
+        );
+        assert_eq!(delta, vec![(regs.a, 2, 5)]);
+    }
+
+    #[test]
+    fn running_doesnt_dump_registers() {
+        // f lives at 0x406, so dumping the registers would fail on this memory
+        let program = assemble("IMM a 1\nIMM b 2".to_string(), consts()).unwrap();
+        let mut mem = vec![0; 0x406];
+        mem[..program.len()].copy_from_slice(&program);
+        let mut e = Emulator::new(mem, consts());
+        assert!(e.dump_registers().is_err());
+        step(&mut e, 2);
+        assert_eq!(e.read_register(consts().registers.b).unwrap(), 2);
+    }
+
+    const WRITE_HI: &str = "IMM a 0x68\nIMM b 0\nSTM b a\nIMM a 0x69\nIMM b 1\nSTM b a\n\
+                            IMM a 1\nIMM b 0\nIMM c 2\nSYS 4 d\nSYS 0x20 d";
+
+    #[test]
+    fn run_capturing_returns_stdout() {
+        let mut e = emulator(WRITE_HI);
+        let (outcome, stdout) = e.run_capturing(100).unwrap();
+        assert_eq!(outcome, RunOutcome::Halted);
+        assert_eq!(stdout, b"hi");
+    }
+
+    #[test]
+    fn run_capturing_keeps_output_out_of_the_installed_io() {
+        let io = MockIo::new();
+        let mut e = emulator(WRITE_HI);
+        e.set_io(Box::new(io.clone()));
+        let (_, stdout) = e.run_capturing(100).unwrap();
+        assert_eq!(stdout, b"hi");
+        assert!(io.stdout().is_empty());
+    }
+
+    #[test]
+    fn line_buffered_read_stops_after_the_newline() {
+        let regs = consts().registers;
+        let io = MockIo::with_stdin(b"hello\nworld\n");
+        io.set_line_buffered(true);
+        let mut e = emulator("IMM a 0\nIMM b 0\nIMM c 0x20\nSYS 1 d");
+        e.set_io(Box::new(io));
+        step(&mut e, 4);
+        assert_eq!(e.read_register(regs.d).unwrap(), 6);
+        assert_eq!(e.read_bytes(0, 7).unwrap(), b"hello\n\0");
+    }
+
+    #[test]
+    fn emulator_can_move_to_another_thread() {
+        let mut e = emulator("IMM a 1\nIMM b 2\nADD a b");
+        e.set_io(Box::new(MockIo::new()));
+        let a = std::thread::spawn(move || {
+            step(&mut e, 3);
+            e.read_register(consts().registers.a).unwrap()
+        });
+        assert_eq!(a.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn set_ip_moves_the_next_fetch() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM a 1\nIMM a 2\nIMM a 3\nIMM a 4\nIMM a 5\nIMM a 6");
+        e.set_ip(5).unwrap();
+        assert_eq!(e.ip().unwrap(), 5);
+        step(&mut e, 1);
+        assert_eq!(e.read_register(regs.a).unwrap(), 6);
+        assert_eq!(e.ip().unwrap(), 6);
+    }
+
+    #[test]
+    fn set_consts_decodes_with_the_new_opcodes() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM a 1");
+        e.set_decode_cache(true);
+        let (instruction, _) = e.step_with_delta().unwrap();
+        assert_eq!(
+            instruction,
+            Instruction::Imm {
+                dst: regs.a,
+                val: 1
+            }
+        );
+
+        let mut swapped = consts();
+        (swapped.opcodes.imm, swapped.opcodes.add) = (swapped.opcodes.add, swapped.opcodes.imm);
+        e.set_consts(swapped).unwrap();
+        e.set_ip(0).unwrap();
+        let (instruction, _) = e.step_with_delta().unwrap();
+        assert_eq!(
+            instruction,
+            Instruction::Add {
+                dst: regs.a,
+                src: 1
+            }
+        );
+    }
+
+    #[test]
+    fn set_consts_rejects_an_invalid_config() {
+        let mut e = emulator("IMM a 1");
+        let mut ctx = consts();
+        ctx.scratch = Some(ctx.registers.i);
+        assert!(e.set_consts(ctx).is_err());
+    }
+
+    #[test]
+    fn data_page_offset_0xff_stays_below_the_registers() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM b 0xff\nIMM c 9\nSTM b c\nLDM d b");
+        e.write_memory(0xff, 7).unwrap();
+        assert_eq!(e.read_memory_raw(0x3ff).unwrap(), 7);
+        assert_eq!(e.read_memory(0xff).unwrap(), 7);
+        step(&mut e, 4);
+        assert_eq!(e.read_memory_raw(0x3ff).unwrap(), 9);
+        assert_eq!(e.read_register(regs.d).unwrap(), 9);
+        assert_eq!(e.read_register(regs.a).unwrap(), 0);
+    }
+
+    #[test]
+    fn disassemble_lists_the_loaded_program() {
+        let src = "IMM a 5\nADD a b\nSTK NONE a\nSYS 4 d\n";
+        let e = emulator(src);
+        assert_eq!(e.disassemble().unwrap(), src);
+        assert_eq!(e.disassemble_code().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn short_writes_are_retried_until_everything_is_written() {
+        let io = MockIo::new();
+        io.set_max_write(Some(1));
+        let mut e = emulator(WRITE_HI);
+        e.set_io(Box::new(io.clone()));
+        assert_eq!(e.run(100).unwrap(), RunOutcome::Halted);
+        assert_eq!(io.stdout(), b"hi");
+        assert_eq!(e.read_register(consts().registers.d).unwrap(), 2);
+    }
+
+    #[test]
+    fn read_bytes_returns_what_write_memory_stored() {
+        let mut e = emulator("");
+        for (i, byte) in [0xde, 0xad, 0xbe, 0xef].into_iter().enumerate() {
+            e.write_memory(0x10 + i as u8, byte).unwrap();
+        }
+        assert_eq!(e.read_bytes(0x10, 4).unwrap(), [0xde, 0xad, 0xbe, 0xef]);
+        assert!(matches!(
+            e.read_bytes(0xfe, 4),
+            Err(EmulationError::InvalidMemoryAddress { address: 0x400 })
+        ));
+    }
+
+    #[test]
+    fn preloaded_buffer_is_written_by_the_program() {
+        let mut e = emulator("IMM a 1\nIMM b 0x20\nIMM c 5\nSYS 4 d\nSYS 0x20 d");
+        e.write_bytes(0x20, b"hello").unwrap();
+        let (outcome, stdout) = e.run_capturing(100).unwrap();
+        assert_eq!(outcome, RunOutcome::Halted);
+        assert_eq!(stdout, b"hello");
+        assert!(e.write_bytes(0xfc, b"hello").is_err());
+    }
+
+    #[test]
+    fn cmp_against_none_tests_for_zero() {
+        let mut e = emulator("CMP a NONE\nIMM a 3\nCMP a NONE");
+        step(&mut e, 1);
+        let flags = e.flags().unwrap();
+        assert!(flags.zero && flags.equals);
+        step(&mut e, 2);
+        let flags = e.flags().unwrap();
+        assert!(!flags.zero && flags.bigger && flags.not_equals);
+    }
+
+    #[test]
+    fn fetching_past_the_code_region_fails() {
+        let mut ctx = consts();
+        ctx.code_region_end = Some(6);
+        let mut e = emulator_with("IMM a 1\nIMM b 2\nIMM c 3", ctx);
+        step(&mut e, 2);
+        assert!(matches!(
+            e.execute_next_instruction(),
+            Err(EmulationError::ExecutingData { address: 6 })
+        ));
+        assert_eq!(e.read_register(consts().registers.c).unwrap(), 0);
+    }
+
+    #[test]
+    fn jumps_are_recorded_taken_or_not() {
+        let ctx = consts();
+        let src = "IMM a 1\nCMP a NONE\nJMP Z end\nJMP G end\nIMM b 1\nend: IMM c 1";
+        let mut e = emulator(src);
+        e.record_events(true);
+        step(&mut e, 6);
+        let jump = |ip, flags_requested, taken| {
+            TraceEvent::Jump(JumpEvent {
+                ip,
+                flags_requested,
+                flags_current: ctx.cmp_flags.bigger | ctx.cmp_flags.not_equals,
+                taken,
+                target: 7,
+            })
+        };
+        let jumps: Vec<_> = e
+            .events()
+            .iter()
+            .filter(|event| matches!(event, TraceEvent::Jump(_)))
+            .copied()
+            .collect();
+        assert_eq!(
+            jumps,
+            vec![
+                jump(3, ctx.cmp_flags.zero, false),
+                jump(5, ctx.cmp_flags.bigger, true)
+            ]
+        );
+        assert_eq!(e.read_register(ctx.registers.b).unwrap(), 0);
+    }
+
+    #[test]
+    fn effective_address_of_ldm_follows_its_source() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM b 0x42");
+        step(&mut e, 1);
+        let ldm = Instruction::Ldm {
+            dst: regs.a,
+            src: regs.b,
+        };
+        assert_eq!(e.effective_address(&ldm).unwrap(), Some(0x342));
+        let add = Instruction::Add {
+            dst: regs.a,
+            src: regs.b,
+        };
+        assert_eq!(e.effective_address(&add).unwrap(), None);
+    }
+
+    #[test]
+    fn add_reads_a_register_by_default() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM a 2\nIMM b 5\nADD a b");
+        step(&mut e, 3);
+        assert_eq!(e.read_register(regs.a).unwrap(), 7);
+    }
+
+    #[test]
+    fn add_takes_a_literal_in_immediate_mode() {
+        let regs = consts().registers;
+        let mut ctx = consts();
+        ctx.add_operand = AddOperandMode::Immediate;
+        let mut e = emulator_with("IMM a 2\nIMM b 5\nADD a 9", ctx);
+        step(&mut e, 3);
+        assert_eq!(e.read_register(regs.a).unwrap(), 11);
+        assert!(assemble("ADD a b".to_string(), ctx).is_err());
+    }
+
+    #[test]
+    fn tracking_writes_returns_every_writer() {
+        let src = "IMM b 0x10\nIMM a 1\nSTM b a\nIMM c 0x11\nSTM c a\nSTM b a\nSYS 0x20 d";
+        let mut e = emulator(src);
+        assert_eq!(e.run_tracking_writes(0x10, 100).unwrap(), vec![2, 5]);
+    }
+
+    #[test]
+    fn halt_opcode_stops_the_run() {
+        let regs = consts().registers;
+        let mut ctx = consts();
+        ctx.opcodes.halt = Some(0x3);
+        let mut e = emulator_with("IMM a 1\nHALT\nIMM a 2", ctx);
+        assert_eq!(e.run(100).unwrap(), RunOutcome::Halted);
+        assert!(e.is_halted());
+        assert_eq!(e.exit_code(), None);
+        assert_eq!(e.read_register(regs.a).unwrap(), 1);
+    }
+
+    #[test]
+    fn read_only_data_rejects_stm() {
+        let mut e = emulator("IMM b 0x10\nIMM a 1\nSTM b a");
+        e.set_protection(0x300..0x400, Prot::READ);
+        step(&mut e, 2);
+        assert!(matches!(
+            e.execute_next_instruction(),
+            Err(EmulationError::ProtectionFault {
+                address: 0x310,
+                access: Access::Write
+            })
+        ));
+        assert_eq!(e.read_memory(0x10).unwrap(), 0);
+    }
+
+    #[test]
+    fn non_executable_code_rejects_fetch_and_read_execute_code_rejects_writes() {
+        let mut e = emulator("IMM a 1");
+        e.set_protection(0..3, Prot::READ_WRITE);
+        assert!(matches!(
+            e.execute_next_instruction(),
+            Err(EmulationError::ProtectionFault {
+                address: 0,
+                access: Access::Execute
+            })
+        ));
+
+        let mut e = emulator("IMM a 1");
+        e.set_protection(0..0x300, Prot::READ_EXECUTE);
+        step(&mut e, 1);
+        assert!(matches!(
+            e.write_memory_raw(0, 0),
+            Err(EmulationError::ProtectionFault {
+                address: 0,
+                access: Access::Write
+            })
+        ));
+    }
+
+    #[test]
+    fn write_syscall_respects_read_protection() {
+        let io = MockIo::new();
+        let mut e = emulator("IMM a 1\nIMM b 0\nIMM c 2\nSYS 4 d");
+        e.set_io(Box::new(io.clone()));
+        e.write_bytes(0, b"hi").unwrap();
+        e.set_protection(0x300..0x400, Prot::NONE);
+        step(&mut e, 3);
+        assert!(matches!(
+            e.execute_next_instruction(),
+            Err(EmulationError::ProtectionFault {
+                address: 0x300,
+                access: Access::Read
+            })
+        ));
+        assert!(io.stdout().is_empty());
+    }
+
+    #[test]
+    fn snapshot_diff_lists_only_differing_registers() {
+        let expected = RegisterSnapshot {
+            a: 1,
+            f: 4,
+            ..Default::default()
+        };
+        let actual = RegisterSnapshot {
+            a: 2,
+            f: 0,
+            ..Default::default()
+        };
+        assert_eq!(expected.diff(&actual), vec![("a", 1, 2), ("f", 4, 0)]);
+        assert!(expected.diff(&expected).is_empty());
+    }
+
+    #[test]
+    fn run_to_stop_reports_a_breakpoint() {
+        let mut e = emulator("IMM a 1\nIMM b 2\nIMM c 3\nSYS 0x20 d");
+        e.add_breakpoint(2);
+        let report = e.run_to_stop(100).unwrap();
+        assert_eq!(report.outcome, RunOutcome::Breakpoint(2));
+        assert_eq!(report.ip, 2);
+        assert_eq!(report.registers.b, 2);
+        assert_eq!(report.registers.c, 0);
+        assert_eq!(report.exit_code, None);
+
+        let report = e.run_to_stop(100).unwrap();
+        assert_eq!(report.outcome, RunOutcome::Halted);
+        assert_eq!(report.exit_code, Some(1));
+    }
+
+    #[test]
+    fn full_trace_records_chain() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM a 4\nIMM b 5");
+        e.set_full_trace(true);
+        step(&mut e, 2);
+        let records = e.full_trace();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].after, records[1].before);
+        assert_eq!(
+            records[0].instruction,
+            Instruction::Imm {
+                dst: regs.a,
+                val: 4
+            }
+        );
+        assert_eq!((records[0].before.a, records[0].after.a), (0, 4));
+        assert_eq!((records[1].before.b, records[1].after.b), (0, 5));
+    }
+
+    #[test]
+    fn verify_program_reports_the_ip_of_a_bad_opcode() {
+        let mut e = emulator("IMM a 1\nIMM b 2\nIMM c 3\nIMM d 4");
+        assert!(e.verify_program().is_ok());
+        e.write_memory_raw(6, 0x03).unwrap();
+        assert!(matches!(
+            e.verify_program(),
+            Err(EmulationError::InvalidInstructionAt {
+                ip: 2,
+                instruction: 0x03
+            })
+        ));
+        assert_eq!(e.cycles(), 0);
+    }
+
+    #[test]
+    fn verify_program_reports_a_truncated_last_instruction() {
+        let mut ctx = consts();
+        ctx.code_region_end = Some(7);
+        let mut e = emulator_with("IMM a 1\nIMM b 2", ctx);
+        e.write_memory_raw(6, 0x01).unwrap();
+        assert!(matches!(
+            e.verify_program(),
+            Err(EmulationError::TruncatedInstruction { offset: 6, len: 1 })
+        ));
+    }
+
+    #[test]
+    fn verify_program_rejects_code_no_ip_can_reach() {
+        let mut ctx = consts();
+        ctx.memory_layout = Some(MemoryLayout {
+            ram_base: 0x400,
+            ram_size: 0x100,
+            reg_base: 0x500,
+        });
+        // 257 IMM a 1, the last one at ip 0x100
+        let mut mem = [0x01, 0x10, 0x01].repeat(0x101);
+        mem.resize(0x507, 0);
+        let e = Emulator::new(mem, ctx);
+        assert!(matches!(
+            e.verify_program(),
+            Err(EmulationError::CodeTooLarge {
+                size: 0x303,
+                max: 0x300
+            })
+        ));
+    }
+
+    #[test]
+    fn clone_steps_independently() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM a 1\nIMM a 2\nIMM a 3");
+        step(&mut e, 1);
+        let mut fork = e.clone();
+        step(&mut fork, 2);
+        assert_eq!(fork.read_register(regs.a).unwrap(), 3);
+        assert_eq!(e.read_register(regs.a).unwrap(), 1);
+        assert_eq!(e.ip().unwrap(), 1);
+        step(&mut e, 1);
+        assert_eq!(e.read_register(regs.a).unwrap(), 2);
+    }
+
+    #[test]
+    fn syscall_budget_stops_the_third_write() {
+        let io = MockIo::new();
+        let mut e = emulator("IMM a 1\nIMM b 0\nIMM c 1\nSYS 4 d\nSYS 4 d\nSYS 4 d");
+        e.set_io(Box::new(io.clone()));
+        e.set_syscall_budget(2);
+        step(&mut e, 5);
+        assert!(matches!(
+            e.execute_next_instruction(),
+            Err(EmulationError::SyscallBudgetExceeded)
+        ));
+        assert_eq!(io.stdout(), vec![0, 0]);
+    }
+
+    #[test]
+    fn push_value_moves_the_stack_like_stk() {
+        let regs = consts().registers;
+        let mut e = emulator("");
+        e.push_value(0x11).unwrap();
+        e.push_value(0x22).unwrap();
+        assert_eq!(e.read_register(regs.s).unwrap(), 2);
+        assert_eq!(e.read_memory(1).unwrap(), 0x11);
+        assert_eq!(e.read_memory(2).unwrap(), 0x22);
+        assert_eq!(e.stack_contents().unwrap(), vec![0x11, 0x22]);
+    }
+
+    #[test]
+    fn pop_value_returns_what_the_program_pushed() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM a 0x33\nSTK NONE a");
+        step(&mut e, 2);
+        assert_eq!(e.pop_value().unwrap(), 0x33);
+        assert_eq!(e.read_register(regs.s).unwrap(), 0);
+        assert!(matches!(e.pop_value(), Err(EmulationError::StackUnderflow)));
+    }
+
+    #[test]
+    fn immediate_mask_applies_to_execution_and_what_is_reported() {
+        let regs = consts().registers;
+        let mut ctx = consts();
+        ctx.immediate_mask = Some(0x0F);
+        let mut e = emulator_with("IMM a 0xFF\nIMM b 0x21", ctx);
+        e.set_full_trace(true);
+        step(&mut e, 1);
+        assert_eq!(e.read_register(regs.a).unwrap(), 0x0F);
+        assert_eq!(
+            e.full_trace()[0].instruction,
+            Instruction::Imm {
+                dst: regs.a,
+                val: 0x0F
+            }
+        );
+        let (instruction, delta) = e.step_with_delta().unwrap();
+        assert_eq!(
+            instruction,
+            Instruction::Imm {
+                dst: regs.b,
+                val: 0x01
+            }
+        );
+        assert_eq!(delta, vec![(regs.b, 0, 0x01)]);
+    }
+
+    const READ_ONE: &str = "IMM a 0\nIMM b 0\nIMM c 1\nIMM d 9\nSYS 1 d\nJMP ALWAYS 3";
+
+    #[test]
+    fn read_at_eof_stores_zero() {
+        let regs = consts().registers;
+        let mut e = emulator(READ_ONE);
+        e.set_io(Box::new(MockIo::new()));
+        step(&mut e, 5);
+        assert_eq!(e.read_register(regs.d).unwrap(), 0);
+        assert!(!e.is_halted());
+    }
+
+    #[test]
+    fn halt_on_eof_stops_the_run() {
+        let mut e = emulator(READ_ONE);
+        e.set_io(Box::new(MockIo::with_stdin(b"xy")));
+        e.set_halt_on_eof(true);
+        assert_eq!(e.run(100).unwrap(), RunOutcome::Halted);
+        assert_eq!(e.read_memory(0).unwrap(), b'y');
+    }
+
+    #[test]
+    fn run_with_io_echoes_stdin() {
+        let src = "IMM a 0\nIMM b 0\nIMM c 5\nSYS 1 c\nIMM a 1\nSYS 4 d\nIMM a 2\nIMM c 1\nSYS 4 d\nSYS 0x20 d";
+        let mut e = emulator(src);
+        let (outcome, stdout, stderr) = e.run_with_io(b"hello", 100).unwrap();
+        assert_eq!(outcome, RunOutcome::Halted);
+        assert_eq!(stdout, b"hello");
+        assert_eq!(stderr, b"h");
+    }
+
+    #[test]
+    fn hook_can_patch_registers_between_steps() {
+        let a = consts().registers.a;
+        let mut e = emulator("IMM b 1\nIMM c 2\nIMM d 3\nSYS 0x20 d");
+        let outcome = e
+            .run_with_hook(100, |e| {
+                let val = e.read_register(a)?;
+                e.try_set_register(a, val + 1)
+            })
+            .unwrap();
+        assert_eq!(outcome, RunOutcome::Halted);
+        assert_eq!(e.exit_code(), Some(4));
+        assert_eq!(e.cycles(), 4);
+    }
+
+    #[test]
+    fn try_set_register_keeps_ip_inside_code() {
+        let i = consts().registers.i;
+        let mut e = emulator("IMM a 1");
+        assert!(e.try_set_register(i, 0xff).is_ok());
+        let mut ctx = consts();
+        ctx.code_region_end = Some(6);
+        let mut e = emulator_with("IMM a 1", ctx);
+        assert!(e.try_set_register(i, 1).is_ok());
+        assert!(matches!(
+            e.try_set_register(i, 2),
+            Err(EmulationError::ExecutingData { address: 6 })
+        ));
+    }
+
+    #[test]
+    fn from_regions_places_code_and_data() {
+        let regs = consts().registers;
+        let code = assemble("IMM b 0x10\nLDM a b\nSYS 0x20 d".to_string(), consts()).unwrap();
+        let mut data = [0; 0x100];
+        data[0x10] = 0x5a;
+        let mut e = Emulator::from_regions(code, data, consts()).unwrap();
+        assert_eq!(e.read_memory(0x10).unwrap(), 0x5a);
+        assert_eq!(e.run(10).unwrap(), RunOutcome::Halted);
+        assert_eq!(e.read_register(regs.a).unwrap(), 0x5a);
+        assert_eq!(e.exit_code(), Some(0x5a));
+    }
+
+    #[test]
+    fn from_regions_rejects_code_overlapping_the_data_page() {
+        assert!(matches!(
+            Emulator::from_regions(vec![0; 0x301], [0; 0x100], consts()),
+            Err(EmulationError::CodeTooLarge {
+                size: 0x301,
+                max: 0x300
+            })
+        ));
+    }
+
+    #[test]
+    fn from_regions_rejects_an_invalid_config() {
+        let mut ctx = consts();
+        ctx.scratch = Some(ctx.registers.s);
+        assert!(matches!(
+            Emulator::from_regions(vec![], [0; 0x100], ctx),
+            Err(EmulationError::InvalidConfig {
+                error: ConfigError::InvalidScratchRegister { .. }
+            })
+        ));
+    }
+
+    #[test]
+    fn execute_runs_each_instruction_in_isolation() {
+        let ctx = consts();
+        let regs = ctx.registers;
+        let mut e = emulator("");
+        let read = |e: &Emulator, register| e.read_register(register).unwrap();
+
+        e.execute(Instruction::Imm {
+            dst: regs.a,
+            val: 5,
+        })
+        .unwrap();
+        e.execute(Instruction::Imm {
+            dst: regs.b,
+            val: 0x10,
+        })
+        .unwrap();
+        assert_eq!(read(&e, regs.a), 5);
+        e.execute(Instruction::Add {
+            dst: regs.a,
+            src: regs.b,
+        })
+        .unwrap();
+        assert_eq!(read(&e, regs.a), 0x15);
+
+        e.execute(Instruction::Stk {
+            pop: REG_NONE,
+            push: regs.a,
+        })
+        .unwrap();
+        assert_eq!((read(&e, regs.s), e.read_memory(1).unwrap()), (1, 0x15));
+        e.execute(Instruction::Stk {
+            pop: regs.c,
+            push: REG_NONE,
+        })
+        .unwrap();
+        assert_eq!((read(&e, regs.s), read(&e, regs.c)), (0, 0x15));
+
+        e.execute(Instruction::Stm {
+            dst: regs.b,
+            src: regs.a,
+        })
+        .unwrap();
+        assert_eq!(e.read_memory(0x10).unwrap(), 0x15);
+        e.execute(Instruction::Ldm {
+            dst: regs.d,
+            src: regs.b,
+        })
+        .unwrap();
+        assert_eq!(read(&e, regs.d), 0x15);
+
+        e.execute(Instruction::Cmp {
+            left: regs.a,
+            right: regs.c,
+        })
+        .unwrap();
+        assert!(e.flags().unwrap().equals);
+        e.execute(Instruction::Jmp {
+            flags: ctx.cmp_flags.equals,
+            dst: regs.b,
+        })
+        .unwrap();
+        assert_eq!(e.ip().unwrap(), 0x10);
+
+        e.execute(Instruction::Sys {
+            num: 0x20,
+            dst: regs.d,
+        })
+        .unwrap();
+        assert_eq!(e.exit_code(), Some(0x15));
+        assert!(e.is_halted());
+        assert_eq!(e.cycles(), 0);
+
+        let mut with_halt = ctx;
+        with_halt.opcodes.halt = Some(0x3);
+        let mut e = emulator_with("", with_halt);
+        e.execute(Instruction::Halt).unwrap();
+        assert!(e.is_halted());
+    }
+
+    #[test]
+    fn write_takes_its_arguments_from_the_configured_registers() {
+        let regs = consts().registers;
+        let mut ctx = consts();
+        ctx.syscall_abi = Some(SyscallAbi {
+            arg1: regs.b,
+            arg2: regs.c,
+            arg3: regs.a,
+        });
+        let io = MockIo::new();
+        let mut e = emulator_with("IMM b 2\nIMM c 0x20\nIMM a 3\nSYS 4 d", ctx);
+        e.write_bytes(0x20, b"abc").unwrap();
+        e.set_io(Box::new(io.clone()));
+        step(&mut e, 4);
+        assert_eq!(io.stderr(), b"abc");
+        assert!(io.stdout().is_empty());
+    }
+
+    #[test]
+    fn run_until_ip_repeat_finds_the_loop_start() {
+        let mut e = emulator("start: IMM b 1\nADD a b\nJMP ALWAYS start");
+        assert_eq!(e.run_until_ip_repeat(100).unwrap(), 0);
+        assert_eq!(e.read_register(consts().registers.a).unwrap(), 1);
+
+        let mut e = emulator("start: IMM b 1\nADD a b\nJMP ALWAYS start");
+        assert!(matches!(
+            e.run_until_ip_repeat(2),
+            Err(EmulationError::StepLimitExceeded)
+        ));
+    }
+
+    #[test]
+    fn add_to_zero_sets_the_zero_flag_when_enabled() {
+        let mut ctx = consts();
+        ctx.flags_on_arith = true;
+        let mut e = emulator_with("IMM a 0xff\nIMM b 1\nADD a b\nADD a b", ctx);
+        step(&mut e, 3);
+        assert!(e.flags().unwrap().zero);
+        step(&mut e, 1);
+        assert!(!e.flags().unwrap().zero);
+
+        let mut e = emulator("IMM a 0xff\nIMM b 1\nADD a b");
+        step(&mut e, 3);
+        assert_eq!(e.flags().unwrap(), FlagSet::default());
+    }
+
+    #[test]
+    fn jump_to_label_clobbers_only_the_scratch_register() {
+        let regs = consts().registers;
+        let mut ctx = consts();
+        ctx.scratch = Some(regs.c);
+        let mut e = emulator_with("IMM d 7\nJMP 0 end\nend: IMM a 1", ctx);
+        step(&mut e, 4);
+        assert_eq!(e.read_register(regs.c).unwrap(), 3);
+        assert_eq!(e.read_register(regs.d).unwrap(), 7);
+        assert_eq!(e.read_register(regs.a).unwrap(), 1);
+    }
+
+    #[test]
+    fn cmp_records_a_compare_event() {
+        let ctx = consts();
+        let regs = ctx.registers;
+        let mut e = emulator("IMM a 7\nIMM b 7\nCMP a b");
+        e.record_events(true);
+        step(&mut e, 3);
+        let flags_set = ctx.cmp_flags.equals;
+        assert_eq!(
+            e.events(),
+            [TraceEvent::Compare(CompareEvent {
+                left_reg: regs.a,
+                right_reg: regs.b,
+                left_val: 7,
+                right_val: 7,
+                flags_set,
+            })]
+        );
+        assert_eq!(
+            ctx.cmp_flags.decode(flags_set),
+            FlagSet {
+                equals: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn run_until_register_eq_stops_when_a_reaches_3() {
+        let a = consts().registers.a;
+        let src = "IMM b 1\nloop: ADD a b\nJMP ALWAYS loop";
+        let mut e = emulator(src);
+        // IMM b, then ADD, IMM scratch, JMP per iteration
+        assert_eq!(e.run_until_register_eq(a, 3, 100).unwrap(), 8);
+        assert_eq!(e.read_register(a).unwrap(), 3);
+
+        let mut e = emulator(src);
+        assert!(matches!(
+            e.run_until_register_eq(a, 3, 7),
+            Err(EmulationError::StepLimitExceeded)
+        ));
+    }
+
+    #[test]
+    fn try_new_rejects_out_of_range_indices() {
+        let mut ctx = consts();
+        ctx.instruction_indices.opcode = 3;
+        assert!(matches!(
+            Emulator::try_new(vec![0; 0x407], ctx),
+            Err(EmulationError::InvalidConfig {
+                error: ConfigError::InvalidDecodeIndices { .. }
+            })
+        ));
+    }
+
+    #[test]
+    fn disassemble_code_pairs_addresses_with_instructions() {
+        let regs = consts().registers;
+        let e = emulator("IMM a 5\nADD a b");
+        assert_eq!(
+            e.disassemble_code().unwrap(),
+            vec![
+                (
+                    0,
+                    Instruction::Imm {
+                        dst: regs.a,
+                        val: 5
+                    }
+                ),
+                (
+                    3,
+                    Instruction::Add {
+                        dst: regs.a,
+                        src: regs.b
+                    }
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn disassemble_code_stops_at_the_end_of_the_code_region() {
+        // The whole code region is IMM a 1, so only its end can stop the listing
+        let program = [1, 0x10, 1].repeat(0x100);
+        let mut mem = vec![0; 0x407];
+        mem[..0x300].copy_from_slice(&program);
+        let e = Emulator::new(mem, consts());
+        assert_eq!(e.disassemble_code().unwrap().len(), 0x100);
+    }
+
+    #[test]
+    fn step_budget_stops_an_infinite_loop() {
+        let mut e = emulator("IMM d 0\nJMP ALWAYS d");
+        assert_eq!(e.run(50).unwrap(), RunOutcome::StepLimitReached);
+        assert_eq!(e.cycles(), 50);
+        assert_eq!(e.run(10).unwrap(), RunOutcome::StepLimitReached);
+        assert_eq!(e.cycles(), 60);
+    }
+
+    #[test]
+    fn recorded_input_replays_to_the_same_result() {
+        let src = "IMM a 0\nIMM b 0\nIMM c 3\nSYS 1 d\nIMM b 3\nSYS 1 d";
+        let mut recorder = emulator(src);
+        recorder.set_io(Box::new(MockIo::with_stdin(b"abcdef")));
+        recorder.record_input(true);
+        step(&mut recorder, 6);
+        assert_eq!(recorder.input_recording(), b"abcdef");
+
+        let mut replayer = emulator(src);
+        replayer.set_io(Box::new(MockIo::new()));
+        replayer.replay_input(recorder.input_recording());
+        step(&mut replayer, 6);
+        assert_eq!(replayer.read_bytes(0, 6).unwrap(), b"abcdef");
+        assert_eq!(
+            replayer.register_snapshot().unwrap(),
+            recorder.register_snapshot().unwrap()
+        );
+    }
+
+    #[test]
+    fn extra_register_is_usable_by_name() {
+        let mut ctx = consts();
+        ctx.registers.extra = &[("e", 0x03, 7)];
+        let program = assemble("IMM e 1\nIMM a 2\nADD a e".to_string(), ctx).unwrap();
+        assert_eq!(program[1], 0x03);
+        assert!(matches!(
+            Emulator::try_new(vec![0; 0x407], ctx),
+            Err(EmulationError::RegisterRegionUnbacked {
+                required: 0x408,
+                ..
+            })
+        ));
+        let mut mem = vec![0; 0x408];
+        mem[..program.len()].copy_from_slice(&program);
+        let mut e = Emulator::try_new(mem, ctx).unwrap();
+        step(&mut e, 3);
+        assert_eq!(e.read_memory_raw(0x407).unwrap(), 1);
+        assert_eq!(e.read_register(ctx.registers.a).unwrap(), 3);
+        assert_eq!(ctx.registers.reg_byte_to_str(0x03), "e");
+    }
+
+    #[test]
+    fn extra_registers_show_up_in_snapshots_and_deltas() {
+        let mut ctx = consts();
+        ctx.registers.extra = &[("e", 0x03, 7)];
+        let mut e = emulator_with("IMM e 1\nIMM e 2", ctx);
+        e.set_full_trace(true);
+        step(&mut e, 1);
+        assert_eq!(e.register_snapshot().unwrap().extra, vec![("e", 1)]);
+        let (_, delta) = e.step_with_delta().unwrap();
+        assert_eq!(delta, vec![(0x03, 1, 2)]);
+        let record = &e.full_trace()[0];
+        assert_eq!(
+            record.before.diff(&record.after),
+            vec![("i", 0, 1), ("e", 0, 1)]
+        );
+    }
+
+    #[test]
+    fn nothing_runs_after_exit() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM a 7\nSYS 0x20 d\nIMM a 1");
+        step(&mut e, 2);
+        assert!(e.is_halted());
+        assert_eq!(e.exit_code(), Some(7));
+        assert!(matches!(
+            e.execute_next_instruction(),
+            Err(EmulationError::Halted)
+        ));
+        assert!(matches!(e.step_with_delta(), Err(EmulationError::Halted)));
+        assert_eq!(e.read_register(regs.a).unwrap(), 7);
+        assert_eq!(e.ip().unwrap(), 2);
+        assert_eq!(e.run(10).unwrap(), RunOutcome::Halted);
+    }
+
+    #[test]
+    fn read_syscall_stores_mock_input_in_memory() {
+        let regs = consts().registers;
+        let mut e = emulator("IMM a 0\nIMM b 0x40\nIMM c 4\nSYS 1 d");
+        e.set_io(Box::new(MockIo::with_stdin(b"\x01\x02\x03\x04\x05")));
+        step(&mut e, 4);
+        assert_eq!(e.read_register(regs.d).unwrap(), 4);
+        assert_eq!(e.read_bytes(0x40, 5).unwrap(), [1, 2, 3, 4, 0]);
+    }
+
+    #[test]
+    fn flags_read_and_write_symbolically() {
+        let mut e = emulator("IMM a 1\nIMM b 2\nCMP a b");
+        let set = FlagSet {
+            zero: true,
+            bigger: true,
+            ..Default::default()
+        };
+        e.set_flags(set).unwrap();
+        assert_eq!(e.flags().unwrap(), set);
+        let f = e.read_register(consts().registers.f).unwrap();
+        assert_eq!(f, consts().cmp_flags.zero | consts().cmp_flags.bigger);
+
+        step(&mut e, 3);
+        assert_eq!(
+            e.flags().unwrap(),
+            FlagSet {
+                smaller: true,
+                not_equals: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn pushing_past_the_top_of_the_stack_is_an_error() {
+        let mut e = emulator("IMM a 7\nloop: STK NONE a\nJMP ALWAYS loop");
+        assert!(matches!(e.run(1000), Err(EmulationError::StackOverflow)));
+        assert_eq!(e.read_register(consts().registers.s).unwrap(), 0xff);
+
+        let mut e = emulator("");
+        e.write_register(consts().registers.s, 0xff).unwrap();
+        assert!(matches!(
+            e.push_value(1),
+            Err(EmulationError::StackOverflow)
+        ));
+    }
+
+    #[test]
+    fn popping_an_empty_stack_is_an_error() {
+        let mut e = emulator("STK c NONE");
+        assert!(matches!(
+            e.execute_next_instruction(),
+            Err(EmulationError::StackUnderflow)
+        ));
+        assert_eq!(e.read_register(consts().registers.s).unwrap(), 0);
+        assert!(matches!(e.pop_value(), Err(EmulationError::StackUnderflow)));
+    }
+
+    #[test]
+    fn from_regions_rejects_an_oversized_data_page() {
+        let mut ctx = consts();
+        ctx.memory_layout = Some(MemoryLayout {
+            ram_base: 0x300,
+            ram_size: 0x200,
+            reg_base: 0x500,
+        });
+        assert!(matches!(
+            Emulator::from_regions(vec![], [0; 0x100], ctx),
+            Err(EmulationError::InvalidConfig {
+                error: ConfigError::InvalidMemoryLayout { .. }
+            })
+        ));
+    }
+
+    #[test]
+    fn syscall_buffers_wrap_into_a_small_data_page() {
+        let mut ctx = consts();
+        ctx.memory_layout = Some(MemoryLayout {
+            ram_base: 0x300,
+            ram_size: 0x10,
+            reg_base: 0x310,
+        });
+        // 200 is offset 8 of a 0x10 byte page, so only the 8 bytes up to the end of the page fit
+        let mut e = emulator_with("IMM a 1\nIMM b 200\nIMM c 20\nSYS 4 d\nSYS 0x20 d", ctx);
+        e.write_bytes(8, b"wrapped!").unwrap();
+        let (outcome, out) = e.run_capturing(10).unwrap();
+        assert_eq!((outcome, out), (RunOutcome::Halted, b"wrapped!".to_vec()));
+
+        let mut e = emulator_with("IMM a 0\nIMM b 200\nIMM c 20\nSYS 1 d", ctx);
+        e.set_io(Box::new(MockIo::with_stdin(b"0123456789")));
+        step(&mut e, 4);
+        assert_eq!(e.read_register(ctx.registers.d).unwrap(), 8);
+        assert_eq!(e.read_bytes(8, 8).unwrap(), b"01234567");
+    }
+
+    #[test]
+    fn syscalls_use_a_shifted_data_page() {
+        let mut ctx = consts();
+        ctx.memory_layout = Some(MemoryLayout {
+            ram_base: 0x200,
+            ram_size: 0x100,
+            reg_base: 0x300,
+        });
+        let mut e = emulator_with(WRITE_HI, ctx);
+        let (outcome, out) = e.run_capturing(20).unwrap();
+        assert_eq!((outcome, out), (RunOutcome::Halted, b"hi".to_vec()));
+        assert_eq!(e.read_memory_raw(0x200).unwrap(), b'h');
+    }
+
+    #[test]
+    fn breakpoint_stops_again_after_stepping_by_hand() {
+        let regs = consts().registers;
+        let mut e = emulator(COUNT_TO_FIVE);
+        e.add_breakpoint(4);
+        assert_eq!(e.run(100).unwrap(), RunOutcome::Breakpoint(4));
+        // CMP, JMP's IMM and JMP take it back around to ADD and CMP
+        step(&mut e, 4);
+        assert_eq!(e.ip().unwrap(), 4);
+        assert_eq!(e.run(100).unwrap(), RunOutcome::Breakpoint(4));
+        assert_eq!(e.read_register(regs.a).unwrap(), 2);
+    }
+
+    #[test]
+    fn run_stops_at_a_breakpoint_and_resumes() {
+        let regs = consts().registers;
+        let mut e = emulator(COUNT_TO_FIVE);
+        // The CMP inside the loop, after ADD a b has run once per pass
+        e.add_breakpoint(4);
+        for pass in 1..=2 {
+            assert_eq!(e.run(100).unwrap(), RunOutcome::Breakpoint(4));
+            assert_eq!(e.read_register(regs.i).unwrap(), 4);
+            assert_eq!(e.read_register(regs.a).unwrap(), pass);
+            assert_eq!(e.read_register(regs.c).unwrap(), 5);
+        }
+        e.remove_breakpoint(4);
+        assert_eq!(e.run(100).unwrap(), RunOutcome::Halted);
+        assert_eq!(e.exit_code(), Some(5));
+    }
+}
@@ -0,0 +1,35 @@
+use std::fmt::Write;
+
+// Define the number of bytes per line in the hexdump
+const BYTES_PER_LINE: usize = 16;
+
+//Classic hexdump, offsets start at base so a slice of memory shows its real addresses
+pub fn hex_dump(data: &[u8], base: usize) -> String {
+    let mut dump = String::new();
+    for (i, chunk) in data.chunks(BYTES_PER_LINE).enumerate() {
+        let _ = write!(dump, "{:08x}: ", base + i * BYTES_PER_LINE);
+
+        // Hexadecimal representation
+        for byte in chunk {
+            let _ = write!(dump, "{:02x} ", byte);
+        }
+
+        // Pad the last line in case the chunk size is smaller than BYTES_PER_LINE
+        for _ in chunk.len()..BYTES_PER_LINE {
+            dump.push_str("   ");
+        }
+
+        // ASCII representation
+        dump.push_str(" | ");
+        for &byte in chunk {
+            if byte.is_ascii_graphic() {
+                dump.push(byte as char);
+            } else {
+                dump.push('.');
+            }
+        }
+
+        dump.push('\n');
+    }
+    dump
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::CString,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+//Host side of the open, read and write syscalls, so they can be served by something other than the real fds.
+//Return values follow libc: the new fd for open, number of bytes transferred otherwise, or negative on error
+pub trait SyscallIo: SyscallIoClone + Send {
+    fn read(&mut self, fd: u8, buffer: &mut [u8]) -> isize;
+    fn write(&mut self, fd: u8, buffer: &[u8]) -> isize;
+    fn open(&mut self, path: &str, flags: u8, mode: u8) -> isize;
+}
+
+//Lets a cloned Emulator clone its io too. Implemented for every SyscallIo that is Clone
+pub trait SyscallIoClone {
+    fn clone_box(&self) -> Box<dyn SyscallIo>;
+}
+
+impl<T: SyscallIo + Clone + 'static> SyscallIoClone for T {
+    fn clone_box(&self) -> Box<dyn SyscallIo> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn SyscallIo> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+//Default io, goes straight to the host's file descriptors
+#[derive(Clone)]
+pub struct LibcIo;
+
+impl SyscallIo for LibcIo {
+    fn read(&mut self, fd: u8, buffer: &mut [u8]) -> isize {
+        unsafe {
+            libc::read(
+                fd.into(),
+                buffer.as_mut_ptr() as *mut std::ffi::c_void,
+                buffer.len(),
+            )
+        }
+    }
+
+    fn write(&mut self, fd: u8, buffer: &[u8]) -> isize {
+        unsafe {
+            libc::write(
+                fd.into(),
+                buffer.as_ptr() as *const std::ffi::c_void,
+                buffer.len(),
+            )
+        }
+    }
+
+    fn open(&mut self, path: &str, flags: u8, mode: u8) -> isize {
+        // A path read out of VM memory stops at the first null, so this only fails on a broken caller
+        let Ok(path) = CString::new(path) else {
+            return -1;
+        };
+        unsafe { libc::open(path.as_ptr(), flags.into(), mode as libc::c_int) as isize }
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    stdin: VecDeque<u8>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    line_buffered: bool,
+    max_write: Option<usize>,
+    files: HashMap<String, Vec<u8>>, // What open can find, by path
+    open_files: Vec<VecDeque<u8>>,   // Unread contents of each opened file, fd 3 first
+}
+
+//In-memory stdin/stdout/stderr. Clones share the same buffers, so keep one around to feed input and inspect output
+//after handing the other to the emulator
+#[derive(Clone, Default)]
+pub struct MockIo {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockIo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Only poisoned if a panic happened mid access, and the buffers can't be trusted after that
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap()
+    }
+
+    pub fn with_stdin(input: &[u8]) -> Self {
+        let io = Self::new();
+        io.push_stdin(input);
+        io
+    }
+
+    pub fn push_stdin(&self, input: &[u8]) {
+        self.state().stdin.extend(input);
+    }
+
+    //Makes each read stop after a newline like a terminal does, so a guest reading a big buffer gets one line at a time
+    pub fn set_line_buffered(&self, enabled: bool) {
+        self.state().line_buffered = enabled;
+    }
+
+    //Caps how many bytes a single write takes, to exercise short writes
+    pub fn set_max_write(&self, max_write: Option<usize>) {
+        self.state().max_write = max_write;
+    }
+
+    //Makes path openable, reads from the fd open returns for it get contents
+    pub fn add_file(&self, path: &str, contents: &[u8]) {
+        self.state()
+            .files
+            .insert(path.to_string(), contents.to_vec());
+    }
+
+    pub fn stdout(&self) -> Vec<u8> {
+        self.state().stdout.clone()
+    }
+
+    pub fn stderr(&self) -> Vec<u8> {
+        self.state().stderr.clone()
+    }
+}
+
+impl SyscallIo for MockIo {
+    fn read(&mut self, fd: u8, buffer: &mut [u8]) -> isize {
+        let mut state = self.state();
+        let line_buffered = state.line_buffered && fd == 0;
+        let input = match fd {
+            0 => &mut state.stdin,
+            fd => match state.open_files.get_mut((fd as usize).wrapping_sub(3)) {
+                Some(file) => file,
+                None => return -1,
+            },
+        };
+        let mut num_read = 0;
+        while num_read < buffer.len() {
+            let Some(byte) = input.pop_front() else {
+                break;
+            };
+            buffer[num_read] = byte;
+            num_read += 1;
+            if line_buffered && byte == b'\n' {
+                break;
+            }
+        }
+        num_read as isize
+    }
+
+    fn write(&mut self, fd: u8, buffer: &[u8]) -> isize {
+        let mut state = self.state();
+        let buffer = &buffer[..buffer.len().min(state.max_write.unwrap_or(usize::MAX))];
+        match fd {
+            1 => state.stdout.extend_from_slice(buffer),
+            2 => state.stderr.extend_from_slice(buffer),
+            _ => return -1,
+        }
+        buffer.len() as isize
+    }
+
+    // Files are only ever opened for reading. fds count up from 3 like they would on a fresh process
+    fn open(&mut self, path: &str, _flags: u8, _mode: u8) -> isize {
+        let mut state = self.state();
+        let Some(contents) = state.files.get(path) else {
+            return -1;
+        };
+        let contents = contents.iter().copied().collect();
+        state.open_files.push(contents);
+        (state.open_files.len() + 2) as isize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn mock_serves_stdin_files_and_collects_output() {
+        let mut io = MockIo::with_stdin(b"abc");
+        io.add_file("input.txt", b"txt");
+        let mut buffer = [0; 2];
+        assert_eq!(io.read(0, &mut buffer), 2);
+        assert_eq!(&buffer, b"ab");
+        assert_eq!(io.open("input.txt", 0, 0), 3);
+        assert_eq!(io.open("/missing", 0, 0), -1);
+        let mut file = [0; 8];
+        assert_eq!(io.read(3, &mut file), 3);
+        assert_eq!(&file[..3], b"txt");
+        assert_eq!(io.read(4, &mut file), -1);
+        assert_eq!(io.write(1, b"out"), 3);
+        assert_eq!(io.write(2, b"err"), 3);
+        assert_eq!(io.write(5, b"x"), -1);
+        assert_eq!(
+            (io.stdout(), io.stderr()),
+            (b"out".to_vec(), b"err".to_vec())
+        );
+    }
+}
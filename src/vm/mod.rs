@@ -1,3 +1,10 @@
+pub mod analysis;
 pub mod arch;
 pub mod assembler;
+pub mod disassembler;
 pub mod emulator;
+pub mod hexdump;
+pub mod io;
+
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,121 @@
+//Config and helpers shared by the unit tests
+use super::{
+    arch::{
+        AddOperandMode, CmpFlags, InstructionDecodeIndices, InstructionOpcodes, Registers,
+        Syscalls, VMConsts,
+    },
+    assembler::assemble,
+    emulator::Emulator,
+};
+
+pub fn consts() -> VMConsts {
+    VMConsts {
+        opcodes: InstructionOpcodes {
+            imm: 0x1,
+            add: 0x10,
+            stk: 0x2,
+            stm: 0x8,
+            ldm: 0x80,
+            cmp: 0x40,
+            jmp: 0x20,
+            sys: 0x4,
+            halt: None,
+        },
+        syscalls: Syscalls {
+            open: 0x8,
+            read_memory: 0x1,
+            write: 0x4,
+            exit: Some(0x20),
+        },
+        registers: Registers {
+            a: 0x10,
+            b: 0x8,
+            c: 0x2,
+            d: 0x40,
+            s: 0x1,
+            i: 0x4,
+            f: 0x20,
+            none: 0x0,
+            extra: &[],
+        },
+        instruction_indices: InstructionDecodeIndices {
+            opcode: 0,
+            left_param: 1,
+            right_param: 2,
+        },
+        cmp_flags: CmpFlags {
+            smaller: 0x2,
+            bigger: 0x1,
+            equals: 0x10,
+            not_equals: 0x8,
+            zero: 0x4,
+        },
+        scratch: None,
+        code_region_end: None,
+        add_operand: AddOperandMode::Register,
+        immediate_mask: None,
+        syscall_abi: None,
+        flags_on_arith: false,
+        memory_layout: None,
+    }
+}
+
+//Assembles src with consts() and loads it at address 0 of a full size memory
+pub fn emulator(src: &str) -> Emulator {
+    emulator_with(src, consts())
+}
+
+pub fn emulator_with(src: &str, consts: VMConsts) -> Emulator {
+    let program = assemble(src.to_string(), consts).unwrap();
+    let registers_end = consts
+        .registers
+        .mem_locations(consts.layout().reg_base)
+        .into_iter()
+        .max()
+        .map_or(0, |location| location as usize + 1);
+    let mut mem = vec![0; registers_end.max(0x407)];
+    mem[..program.len()].copy_from_slice(&program);
+    Emulator::new(mem, consts)
+}
+
+//Panics with every differing byte if src doesn't assemble to expected_hex, written like "01 10 05"
+pub fn assert_assembles_to(src: &str, ctx: VMConsts, expected_hex: &str) {
+    let expected: Vec<u8> = expected_hex
+        .split_whitespace()
+        .map(|byte| {
+            u8::from_str_radix(byte, 16)
+                .unwrap_or_else(|_| panic!("Invalid hex byte in expected output: {}", byte))
+        })
+        .collect();
+    let actual = assemble(src.to_string(), ctx).unwrap_or_else(|err| panic!("{}", err));
+    if actual == expected {
+        return;
+    }
+
+    let mut diff = String::new();
+    for i in 0..actual.len().max(expected.len()) {
+        let byte = |bytes: &[u8]| {
+            bytes
+                .get(i)
+                .map_or("--".to_string(), |b| format!("{:02x}", b))
+        };
+        if actual.get(i) != expected.get(i) {
+            diff.push_str(&format!(
+                "\n  byte {}: expected {}, got {}",
+                i,
+                byte(&expected),
+                byte(&actual)
+            ));
+        }
+    }
+    panic!(
+        "Assembled output doesn't match\n  expected: {}\n  actual:   {}{}",
+        expected_hex.trim(),
+        actual
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<String>>()
+            .join(" "),
+        diff
+    );
+}